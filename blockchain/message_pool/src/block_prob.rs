@@ -3,6 +3,7 @@
 
 use std::f64::consts::E;
 
+use once_cell::sync::Lazy;
 use statrs::function::gamma::ln_gamma;
 
 const MAX_BLOCKS: usize = 15;
@@ -19,21 +20,22 @@ fn poiss_pdf(x: f64, mu: f64, cond: f64) -> f64 {
 // * This will be needed for optimal message selection
 #[cfg(test)]
 #[cfg(feature = "slow_tests")]
-// TODO following two can be lazy_static
 fn no_winners_prob() -> Vec<f64> {
     (0..MAX_BLOCKS)
         .map(|i| poiss_pdf(i as f64, MU, MU))
         .collect()
 }
 
-/// Calculate the number of winners for each block number, up to [`MAX_BLOCKS`],
-/// assuming at least one winner.
-fn no_winners_prob_assuming_more_than_one() -> Vec<f64> {
+/// Probability of the number of winners, assuming at least one winner. Index
+/// `i` holds the probability of `i + 1` winners, i.e. of `i` other winners
+/// besides the miner. It does not depend on the ticket quality, so it is only
+/// computed once.
+static NO_WINNERS_PROB_ASSUMING_MORE_THAN_ONE: Lazy<Vec<f64>> = Lazy::new(|| {
     let cond = (E.powf(5.0) - 1.0).log(E);
     (0..MAX_BLOCKS)
-        .map(|i| poiss_pdf(i as f64, MU, cond))
+        .map(|i| poiss_pdf((i + 1) as f64, MU, cond))
         .collect()
-}
+});
 
 fn binomial_coefficient(mut n: f64, k: f64) -> Result<f64, ()> {
     if k > n {
@@ -78,12 +80,13 @@ fn bino_pdf(x: f64, trials: f64, p: f64) -> f64 {
     coef * pow
 }
 
+/// Probability of a block ending up at each position of a tipset, up to
+/// [`MAX_BLOCKS`], for a miner with the given ticket quality.
 pub fn block_probabilities(tq: f64) -> Vec<f64> {
-    let no_winners = no_winners_prob_assuming_more_than_one();
     let p = 1.0 - tq;
     (0..MAX_BLOCKS)
         .map(|place| {
-            no_winners
+            NO_WINNERS_PROB_ASSUMING_MORE_THAN_ONE
                 .iter()
                 .enumerate()
                 .map(|(other_winner, p_case)| {
@@ -102,6 +105,27 @@ fn test_block_probability() {
     }
 }
 
+#[test]
+fn test_block_probabilities_sum() {
+    for tq in [0.1, 0.5, 0.85, 1.0] {
+        let bp = block_probabilities(tq);
+        assert_eq!(bp.len(), MAX_BLOCKS);
+        // every case of the winner distribution places the block somewhere; only
+        // the cases of more than `MAX_BLOCKS` winners are missing
+        let sum: f64 = bp.iter().sum();
+        assert!(
+            (sum - 1.0).abs() < 1e-3,
+            "tq {tq}: probabilities sum to {sum}"
+        );
+        // a good ticket is most likely to be first, a bad one is not
+        if tq >= 0.85 {
+            assert!(bp.windows(2).all(|w| w[0] >= w[1]));
+        } else {
+            assert!(bp[1] > bp[0], "tq {tq}: {bp:?}");
+        }
+    }
+}
+
 #[test]
 #[cfg(feature = "slow_tests")]
 fn test_winner_probability() {
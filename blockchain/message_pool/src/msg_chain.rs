@@ -7,19 +7,22 @@ use std::{
     ops::{Index, IndexMut},
 };
 
-use ahash::HashMap;
+use ahash::{HashMap, HashSet};
+use cid::Cid;
 use forest_blocks::Tipset;
 use forest_message::{Message, SignedMessage};
 use forest_networks::ChainConfig;
+use forest_shim::version::NetworkVersion;
 use fvm::gas::{price_list_by_network_version, Gas};
 use fvm_ipld_encoding::Cbor;
 use fvm_shared::{address::Address, econ::TokenAmount};
-use log::warn;
+use log::{debug, warn};
 use num_traits::Zero;
 use slotmap::{new_key_type, SlotMap};
 
 use super::errors::Error;
 use crate::{
+    msgpool::MAX_BLOCK_MSGS,
     provider::Provider,
     utils::{get_gas_perf, get_gas_reward},
 };
@@ -160,6 +163,16 @@ impl Chains {
         self.map.len()
     }
 
+    /// Iterates over the nodes that haven't been invalidated, in `key_vec`
+    /// order.
+    #[cfg(test)]
+    pub(crate) fn iter_valid(&self) -> impl Iterator<Item = &MsgChainNode> {
+        self.key_vec
+            .iter()
+            .filter_map(|k| self.map.get(*k))
+            .filter(|node| node.valid)
+    }
+
     /// Returns true is the chain is empty and otherwise. We check the map as
     /// the source of truth as `key_vec` can be extended time to time.
     pub(crate) fn is_empty(&self) -> bool {
@@ -167,7 +180,7 @@ impl Chains {
     }
 
     /// Removes messages from the given index and resets effective `perfs`
-    pub(crate) fn trim_msgs_at(&mut self, idx: usize, gas_limit: i64, base_fee: &TokenAmount) {
+    pub(crate) fn trim_msgs_at(&mut self, idx: usize, gas_limit: i64, ctx: &SelectionContext) {
         let prev = match idx {
             0 => None,
             _ => self
@@ -177,8 +190,13 @@ impl Chains {
         let chain_node = self.get_mut_at(idx).unwrap();
         let mut i = chain_node.msgs.len() as i64 - 1;
 
-        while i >= 0 && (chain_node.gas_limit > gas_limit || (chain_node.gas_perf < 0.0)) {
-            let gas_reward = get_gas_reward(&chain_node.msgs[i as usize], base_fee);
+        // non-finite performance can't be ordered, so such messages are dropped too
+        while i >= 0
+            && (chain_node.gas_limit > gas_limit
+                || chain_node.gas_perf < 0.0
+                || !chain_node.gas_perf.is_finite())
+        {
+            let gas_reward = get_gas_reward(&chain_node.msgs[i as usize], &ctx.base_fee);
             chain_node.gas_reward -= gas_reward;
             chain_node.gas_limit -= chain_node.msgs[i as usize].gas_limit();
             if chain_node.gas_limit > 0 {
@@ -195,9 +213,10 @@ impl Chains {
 
         if i < 0 {
             chain_node.msgs.clear();
+            chain_node.first_msg_cid = None;
             chain_node.valid = false;
         } else {
-            chain_node.msgs.drain(0..i as usize + 1);
+            chain_node.msgs.truncate(i as usize + 1);
         }
 
         let next = chain_node.next;
@@ -219,9 +238,94 @@ impl Chains {
             if let Some(node) = self.map.get_mut(*k) {
                 node.valid = false;
                 node.msgs.clear();
+                node.first_msg_cid = None;
                 node.next = None;
             }
         }
+        debug_assert_eq!(self.validate_links(), Ok(()));
+    }
+
+    /// Removes the node at the given index and links its neighbours to each
+    /// other. Pointers are `NodeKey`s, so the remaining nodes keep theirs.
+    #[cfg(test)] // no selection step compacts chains yet.
+    pub(crate) fn drop_node(&mut self, idx: usize) -> Option<MsgChainNode> {
+        if idx >= self.key_vec.len() {
+            return None;
+        }
+        let key = self.key_vec.remove(idx);
+        let node = self.map.remove(key)?;
+        if let Some(prev) = node.prev.and_then(|k| self.map.get_mut(k)) {
+            prev.next = node.next;
+        }
+        if let Some(next) = node.next.and_then(|k| self.map.get_mut(k)) {
+            next.prev = node.prev;
+        }
+        debug_assert_eq!(self.validate_links(), Ok(()));
+        Some(node)
+    }
+
+    /// Appends a node after the last one in `key_vec`, linking the two, and
+    /// returns the index of the new node. Any links already set on `node` are
+    /// overwritten.
+    #[cfg(test)] // chain creation still links per-sender node vecs itself.
+    pub(crate) fn append_node(&mut self, mut node: MsgChainNode) -> usize {
+        let tail = self.key_vec.last().copied();
+        node.prev = tail;
+        node.next = None;
+        let key = self.map.insert(node);
+        if let Some(tail) = tail.and_then(|k| self.map.get_mut(k)) {
+            tail.next = Some(key);
+        }
+        self.key_vec.push(key);
+        debug_assert_eq!(self.validate_links(), Ok(()));
+        self.key_vec.len() - 1
+    }
+
+    /// Checks that every `next`/`prev` pointer refers to a node in the map,
+    /// that each `next` pointer is mirrored by the `prev` pointer of its target
+    /// and that following `next` pointers never loops. Invalidation clears
+    /// `next` pointers only, so a `prev` whose target no longer links back to
+    /// it is allowed, but a `prev` to a key missing from the map is an error.
+    pub(crate) fn validate_links(&self) -> Result<(), String> {
+        let mut targets = HashSet::default();
+        for (key, node) in self.map.iter() {
+            if let Some(prev) = node.prev {
+                if !self.map.contains_key(prev) {
+                    return Err(format!("node {key:?} has dangling prev pointer {prev:?}"));
+                }
+            }
+            if let Some(next) = node.next {
+                let next_node = self
+                    .map
+                    .get(next)
+                    .ok_or_else(|| format!("node {key:?} has dangling next pointer {next:?}"))?;
+                if next_node.prev != Some(key) {
+                    return Err(format!(
+                        "node {key:?} points to {next:?}, which points back to {:?}",
+                        next_node.prev
+                    ));
+                }
+                targets.insert(next);
+            }
+        }
+
+        // mirrored `next` pointers give every node at most one predecessor, so
+        // any node not reachable from a head must be part of a cycle
+        let mut reachable = 0;
+        for key in self.map.keys().filter(|k| !targets.contains(k)) {
+            let mut cur = Some(key);
+            while let Some(k) = cur {
+                reachable += 1;
+                cur = self.map.get(k).and_then(|n| n.next);
+            }
+        }
+        if reachable != self.map.len() {
+            return Err(format!(
+                "{} nodes are part of a cycle of next pointers",
+                self.map.len() - reachable
+            ));
+        }
+        Ok(())
     }
 
     /// Drops nodes which are no longer valid after the merge step
@@ -266,6 +370,9 @@ pub struct MsgChainNode {
     pub merged: bool,
     pub next: Option<NodeKey>,
     pub prev: Option<NodeKey>,
+    /// CID of the first message, computed once when the node is created as
+    /// it breaks ties in [`MsgChainNode::cmp_effective`].
+    pub first_msg_cid: Option<Cid>,
 }
 
 impl MsgChainNode {
@@ -289,6 +396,10 @@ impl MsgChainNode {
             || (approx_cmp(self.eff_perf, other.eff_perf) == Ordering::Equal
                 && approx_cmp(self.gas_perf, other.gas_perf) == Ordering::Equal
                 && self.gas_reward > other.gas_reward)
+            || (approx_cmp(self.eff_perf, other.eff_perf) == Ordering::Equal
+                && approx_cmp(self.gas_perf, other.gas_perf) == Ordering::Equal
+                && self.gas_reward == other.gas_reward
+                && self.first_msg_cid < other.first_msg_cid)
         {
             return Ordering::Greater;
         }
@@ -296,14 +407,54 @@ impl MsgChainNode {
         Ordering::Less
     }
 
+    /// Returns a copy of the node keeping only its first `n` messages, with the
+    /// gas fields recomputed and the link to the next node dropped, so that a
+    /// prefix of the chain can be tried for inclusion without mutating it.
+    #[cfg(test)] // no selection step packs speculatively yet.
+    pub(crate) fn clone_truncated(&self, n: usize, ctx: &SelectionContext) -> MsgChainNode {
+        let msgs: Vec<SignedMessage> = self.msgs.iter().take(n).cloned().collect();
+        let gas_reward = msgs.iter().fold(TokenAmount::zero(), |acc, m| {
+            acc + get_gas_reward(m, &ctx.base_fee)
+        });
+        let gas_limit = msgs.iter().map(|m| m.gas_limit()).sum();
+        let gas_perf = if gas_limit > 0 {
+            get_gas_perf(&gas_reward, gas_limit)
+        } else {
+            0.0
+        };
+        let mut node = MsgChainNode {
+            msgs,
+            gas_reward,
+            gas_limit,
+            gas_perf,
+            eff_perf: 0.0,
+            bp: self.bp,
+            parent_offset: 0.0,
+            valid: self.valid,
+            merged: self.merged,
+            next: None,
+            prev: self.prev,
+            first_msg_cid: self.first_msg_cid.filter(|_| n > 0),
+        };
+        node.set_eff_perf(None);
+        node
+    }
+
     pub fn set_null_effective_perf(&mut self) {
         if self.gas_perf < 0.0 {
-            self.eff_perf = self.gas_perf;
+            self.eff_perf = finite_or_zero(self.gas_perf, "eff_perf");
         } else {
             self.eff_perf = 0.0;
         }
     }
 
+    /// Records the probability of the block the chain was partitioned into and
+    /// sets the effective performance accordingly.
+    pub fn set_eff_perf_with_block_prob(&mut self, bp: f64) {
+        self.bp = bp;
+        self.set_eff_perf(None);
+    }
+
     pub fn set_eff_perf(&mut self, prev: Option<(f64, i64)>) {
         let mut eff_perf = self.gas_perf * self.bp;
         if let Some(prev) = prev {
@@ -313,11 +464,23 @@ impl MsgChainNode {
                 let eff_perf_with_parent = (eff_perf * self.gas_limit as f64
                     + prev_eff_perf * prev_gas_limit as f64)
                     / (self.gas_limit + prev_gas_limit) as f64;
-                self.parent_offset = eff_perf - eff_perf_with_parent;
+                self.parent_offset =
+                    finite_or_zero(eff_perf - eff_perf_with_parent, "parent_offset");
                 eff_perf = eff_perf_with_parent;
             }
         }
-        self.eff_perf = eff_perf;
+        self.eff_perf = finite_or_zero(eff_perf, "eff_perf");
+    }
+}
+
+/// Returns `value` if it is finite and `0.0` otherwise, so that the stored
+/// performance fields can always be ordered by `approx_cmp`.
+fn finite_or_zero(value: f64, field: &str) -> f64 {
+    if value.is_finite() {
+        value
+    } else {
+        debug!("replacing non-finite {field} {value} with 0");
+        0.0
     }
 }
 
@@ -335,16 +498,58 @@ impl std::default::Default for MsgChainNode {
             merged: false,
             next: None,
             prev: None,
+            first_msg_cid: None,
+        }
+    }
+}
+
+/// Block building parameters shared by every step of message selection, so
+/// that chain creation and trimming always score messages against the same
+/// base fee.
+#[derive(Clone, Debug)]
+pub(crate) struct SelectionContext<'a> {
+    /// The tipset the new block builds on.
+    pub ts: &'a Tipset,
+    pub base_fee: TokenAmount,
+    pub block_gas_limit: i64,
+    /// Maximum number of messages selected from a single sender.
+    pub max_per_sender: usize,
+}
+
+impl<'a> SelectionContext<'a> {
+    pub(crate) fn new(ts: &'a Tipset, base_fee: TokenAmount) -> Self {
+        Self {
+            ts,
+            base_fee,
+            block_gas_limit: fvm_shared::BLOCK_GAS_LIMIT,
+            max_per_sender: MAX_BLOCK_MSGS,
         }
     }
+
+    /// Creates a context whose block gas limit is the one in effect at the
+    /// network version of `ts`.
+    pub(crate) fn with_network(
+        ts: &'a Tipset,
+        base_fee: TokenAmount,
+        chain_config: &ChainConfig,
+    ) -> Self {
+        let mut ctx = Self::new(ts, base_fee);
+        ctx.block_gas_limit = block_gas_limit(chain_config.network_version(ts.epoch()));
+        ctx
+    }
+}
+
+/// Returns the block gas limit of the given network version. The limit has
+/// not changed in any upgrade so far.
+pub(crate) fn block_gas_limit(_network_version: NetworkVersion) -> i64 {
+    fvm_shared::BLOCK_GAS_LIMIT
 }
 
 pub(crate) fn create_message_chains<T>(
     api: &T,
     actor: &Address,
     mset: &HashMap<u64, SignedMessage>,
-    base_fee: &TokenAmount,
-    ts: &Tipset,
+    ctx: &SelectionContext,
     chains: &mut Chains,
     chain_config: &ChainConfig,
 ) -> Result<(), Error>
@@ -363,7 +568,7 @@ where
     //   limit
     // - the total gasReward cannot exceed the actor's balance; drop all messages
     //   that exceed the balance
-    let actor_state = api.get_actor_after(actor, ts)?;
+    let actor_state = api.get_actor_after(actor, ctx.ts)?;
     let mut cur_seq = actor_state.sequence;
     let mut balance: TokenAmount =
         forest_shim::econ::TokenAmount::from(&actor_state.balance).into();
@@ -387,12 +592,12 @@ where
             continue;
         }
 
-        if m.sequence() != cur_seq {
+        if m.sequence() != cur_seq || i - skip == ctx.max_per_sender {
             break;
         }
         cur_seq += 1;
 
-        let network_version = chain_config.network_version(ts.epoch());
+        let network_version = chain_config.network_version(ctx.ts.epoch());
 
         let min_gas = price_list_by_network_version(network_version.into())
             .on_chain_message(m.marshal_cbor()?.len())
@@ -402,7 +607,7 @@ where
             break;
         }
         gas_limit += m.gas_limit();
-        if gas_limit > fvm_shared::BLOCK_GAS_LIMIT {
+        if gas_limit > ctx.block_gas_limit {
             break;
         }
 
@@ -415,7 +620,7 @@ where
         let value = m.value();
         balance -= value;
 
-        let gas_reward = get_gas_reward(m, base_fee);
+        let gas_reward = get_gas_reward(m, &ctx.base_fee);
         rewards.push(gas_reward);
         i += 1;
     }
@@ -432,6 +637,7 @@ where
 
     let new_chain = |m: SignedMessage, i: usize| -> MsgChainNode {
         let gl = m.gas_limit();
+        let first_msg_cid = m.cid().ok();
         MsgChainNode {
             msgs: vec![m],
             gas_reward: rewards[i].clone(),
@@ -444,6 +650,7 @@ where
             merged: false,
             prev: None,
             next: None,
+            first_msg_cid,
         }
     };
 
@@ -543,3 +750,216 @@ fn approx_cmp(a: f64, b: f64) -> Ordering {
         a.partial_cmp(&b).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgpool::{test_provider::mock_block, tests::create_fake_smsg};
+
+    /// Creates `n` default nodes linked in insertion order.
+    fn linked_chains(n: usize) -> Chains {
+        let mut chains = Chains::new();
+        let mut node_vec = vec![];
+        for _ in 0..n {
+            chains.push_with(MsgChainNode::default(), &mut node_vec);
+        }
+        for i in 0..n.saturating_sub(1) {
+            chains.get_mut(node_vec[i]).unwrap().next = Some(node_vec[i + 1]);
+            chains.get_mut(node_vec[i + 1]).unwrap().prev = Some(node_vec[i]);
+        }
+        chains.key_vec.extend(node_vec);
+        chains
+    }
+
+    #[test]
+    fn iter_valid_skips_invalidated_nodes() {
+        let mut chains = linked_chains(3);
+        for i in 0..3 {
+            chains[i].gas_limit = i as i64 + 1;
+        }
+        chains[1].valid = false;
+
+        let gas_limits: Vec<i64> = chains.iter_valid().map(|n| n.gas_limit).collect();
+        assert_eq!(gas_limits, vec![1, 3]);
+    }
+
+    #[test]
+    fn perf_setters_stay_finite_with_zero_gas_limits() {
+        let mut node = MsgChainNode {
+            gas_perf: 1.0,
+            bp: 1.0,
+            ..Default::default()
+        };
+        node.set_eff_perf(Some((1.0, 0)));
+        assert!(node.eff_perf.is_finite());
+        assert!(node.parent_offset.is_finite());
+
+        node.gas_perf = f64::NAN;
+        node.set_eff_perf_with_block_prob(0.5);
+        assert_eq!(node.bp, 0.5);
+        assert!(node.eff_perf.is_finite());
+
+        node.gas_perf = f64::NEG_INFINITY;
+        node.set_null_effective_perf();
+        assert!(node.eff_perf.is_finite());
+    }
+
+    #[test]
+    fn clone_truncated_recomputes_gas_fields() {
+        let ts = Tipset::from(mock_block(1, 1));
+        let ctx = SelectionContext::new(&ts, TokenAmount::from_atto(100));
+        let msgs: Vec<SignedMessage> = (0..3)
+            .map(|i| create_fake_smsg(i, 1000 * (i as i64 + 1), 200, 10 * (i + 1)))
+            .collect();
+        let mut chains = linked_chains(2);
+        chains[0].msgs = msgs;
+        chains[0].gas_limit = 6000;
+        chains[0].bp = 0.5;
+        let original = chains[0].clone();
+
+        let truncated = chains[0].clone_truncated(2, &ctx);
+
+        // rewards are 10 * 1000 and 20 * 2000
+        let gas_reward = TokenAmount::from_atto(50_000);
+        assert_eq!(truncated.msgs.len(), 2);
+        assert_eq!(truncated.gas_limit, 3000);
+        assert_eq!(truncated.gas_reward, gas_reward);
+        assert_eq!(truncated.gas_perf, get_gas_perf(&gas_reward, 3000));
+        assert_eq!(truncated.eff_perf, truncated.gas_perf * 0.5);
+        assert_eq!(truncated.next, None);
+
+        assert_eq!(chains[0].msgs, original.msgs);
+        assert_eq!(chains[0].gas_limit, 6000);
+        assert!(chains[0].next.is_some());
+    }
+
+    #[test]
+    fn trim_long_chain_keeps_prefix() {
+        let ts = Tipset::from(mock_block(1, 1));
+        let ctx = SelectionContext::new(&ts, TokenAmount::zero());
+        let mut chains = linked_chains(1);
+        chains[0].msgs = (0..10_000)
+            .map(|i| create_fake_smsg(i, 1000, 200, 10))
+            .collect();
+        chains[0].gas_limit = 10_000_000;
+        chains[0].gas_reward = TokenAmount::from_atto(10 * 10_000_000);
+        chains[0].gas_perf = get_gas_perf(&chains[0].gas_reward, chains[0].gas_limit);
+
+        chains.trim_msgs_at(0, 4_000_000, &ctx);
+
+        let node = &chains[0];
+        let sequences: Vec<u64> = node.msgs.iter().map(|m| m.sequence()).collect();
+        assert_eq!(sequences, (0..4000).collect::<Vec<_>>());
+        assert_eq!(node.gas_limit, 4_000_000);
+        assert_eq!(node.gas_reward, TokenAmount::from_atto(10 * 4_000_000));
+        assert_eq!(
+            node.gas_perf,
+            get_gas_perf(&node.gas_reward, node.gas_limit)
+        );
+        assert!(node.valid);
+    }
+
+    #[test]
+    fn drop_node_relinks_neighbours() {
+        let mut chains = linked_chains(3);
+        let first = chains.key_vec[0];
+        let last = chains.key_vec[2];
+
+        assert!(chains.drop_node(1).is_some());
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains.key_vec, vec![first, last]);
+        assert_eq!(chains[0].prev, None);
+        assert_eq!(chains[0].next, Some(last));
+        assert_eq!(chains[1].prev, Some(first));
+        assert_eq!(chains[1].next, None);
+
+        assert!(chains.drop_node(2).is_none());
+    }
+
+    #[test]
+    fn append_node_links_to_tail() {
+        let mut chains = Chains::new();
+        for i in 0..3 {
+            let node = MsgChainNode {
+                gas_limit: i as i64,
+                ..Default::default()
+            };
+            assert_eq!(chains.append_node(node), i);
+        }
+        assert_eq!(chains.validate_links(), Ok(()));
+
+        let keys = chains.key_vec.clone();
+        assert_eq!(chains[0].prev, None);
+        assert_eq!(chains[0].next, Some(keys[1]));
+        assert_eq!(chains[1].prev, Some(keys[0]));
+        assert_eq!(chains[1].next, Some(keys[2]));
+        assert_eq!(chains[2].prev, Some(keys[1]));
+        assert_eq!(chains[2].next, None);
+        assert_eq!(chains[2].gas_limit, 2);
+    }
+
+    #[test]
+    fn cmp_effective_breaks_ties_by_first_message_cid() {
+        let node = |sequence| {
+            let msg = create_fake_smsg(sequence, 0, 0, 0);
+            MsgChainNode {
+                first_msg_cid: msg.cid().ok(),
+                msgs: vec![msg],
+                gas_reward: TokenAmount::from_atto(10),
+                gas_limit: 100,
+                gas_perf: 1.0,
+                eff_perf: 1.0,
+                ..MsgChainNode::default()
+            }
+        };
+        let (a, b) = (node(1), node(2));
+
+        assert_ne!(a.cmp_effective(&b), b.cmp_effective(&a));
+
+        let mut forward = vec![a.clone(), b.clone()];
+        let mut backward = vec![b, a];
+        forward.sort_by(|x, y| x.cmp_effective(y));
+        backward.sort_by(|x, y| x.cmp_effective(y));
+        assert_eq!(forward[0].msgs, backward[0].msgs);
+        assert_eq!(forward[1].msgs, backward[1].msgs);
+    }
+
+    #[test]
+    fn validate_links_detects_inconsistencies() {
+        let mut chains = linked_chains(3);
+        assert_eq!(chains.validate_links(), Ok(()));
+
+        // skipping the middle node without fixing its `prev` pointer
+        let last = chains.key_vec[2];
+        chains[0].next = Some(last);
+        assert!(chains.validate_links().is_err());
+
+        // closing the list into a cycle
+        let mut chains = linked_chains(3);
+        let (first, last) = (chains.key_vec[0], chains.key_vec[2]);
+        chains[2].next = Some(first);
+        chains[0].prev = Some(last);
+        assert!(chains.validate_links().is_err());
+
+        // pointing at a removed node
+        let mut chains = linked_chains(2);
+        let removed = chains.key_vec[1];
+        chains.map.remove(removed);
+        assert!(chains.validate_links().is_err());
+    }
+
+    #[test]
+    fn huge_gas_reward_keeps_perf_finite() {
+        let huge = TokenAmount::from_atto(num::BigInt::from(10).pow(400));
+        let perf = get_gas_perf(&huge, 1);
+        assert_eq!(perf, f64::MAX);
+
+        let mut chains = linked_chains(2);
+        chains[0].gas_perf = get_gas_perf(&TokenAmount::from_atto(1), 1);
+        chains[1].gas_perf = perf;
+        chains[1].gas_reward = huge;
+        chains.sort(true);
+        assert!(chains.iter_valid().all(|n| !n.gas_perf.is_nan()));
+        assert_eq!(chains[0].gas_perf, f64::MAX);
+    }
+}
@@ -20,12 +20,14 @@ use fvm_shared::{address::Address, crypto::signature::Signature};
 use log::error;
 use lru::LruCache;
 use parking_lot::{Mutex, RwLock as SyncRwLock};
+pub use selection::SelectionMode;
 use tokio::sync::broadcast::{Receiver as Subscriber, Sender as Publisher};
 use utils::{get_base_fee_lower_bound, recover_sig};
+pub use utils::{get_gas_perf, get_gas_reward};
 
 use super::errors::Error;
 use crate::{
-    msg_chain::{create_message_chains, Chains},
+    msg_chain::{create_message_chains, Chains, SelectionContext},
     msg_pool::{add_helper, remove, MsgSet},
     provider::Provider,
 };
@@ -39,6 +41,8 @@ const REPUB_MSG_LIMIT: usize = 30;
 const PROPAGATION_DELAY_SECS: u64 = 6;
 // TODO: Implement guess gas module
 const MIN_GAS: i64 = 1298450;
+// A cap on maximum number of message to include in a block
+pub(crate) const MAX_BLOCK_MSGS: usize = 16000;
 
 /// Get the state of the `base_sequence` for a given address in the current
 /// Tipset
@@ -123,8 +127,14 @@ where
 {
     let mut msgs: Vec<SignedMessage> = vec![];
 
-    let base_fee = api.chain_compute_base_fee(base)?;
-    let base_fee_lower_bound = get_base_fee_lower_bound(&base_fee, BASE_FEE_LOWER_BOUND_FACTOR);
+    let ctx = SelectionContext::with_network(base, api.chain_compute_base_fee(base)?, chain_config);
+    let base_fee_lower_bound = get_base_fee_lower_bound(&ctx.base_fee, BASE_FEE_LOWER_BOUND_FACTOR);
+    // chains are scored against the lower bound so that messages which become
+    // includable within the next 20 blocks are still republished
+    let chain_ctx = SelectionContext {
+        base_fee: base_fee_lower_bound.clone(),
+        ..ctx.clone()
+    };
 
     if pending.is_empty() {
         return Ok(msgs);
@@ -132,15 +142,7 @@ where
 
     let mut chains = Chains::new();
     for (actor, mset) in pending.iter() {
-        create_message_chains(
-            api,
-            actor,
-            mset,
-            &base_fee_lower_bound,
-            base,
-            &mut chains,
-            chain_config,
-        )?;
+        create_message_chains(api, actor, mset, &chain_ctx, &mut chains, chain_config)?;
     }
 
     if chains.is_empty() {
@@ -149,7 +151,7 @@ where
 
     chains.sort(false);
 
-    let mut gas_limit = fvm_shared::BLOCK_GAS_LIMIT;
+    let mut gas_limit = ctx.block_gas_limit;
     let mut i = 0;
     'l: while i < chains.len() {
         let chain = &mut chains[i];
@@ -188,7 +190,7 @@ where
 
         // we can't fit the current chain but there is gas to spare
         // trim it and push it down
-        chains.trim_msgs_at(i, gas_limit, &base_fee);
+        chains.trim_msgs_at(i, gas_limit, &ctx);
         let mut j = i;
         while j < chains.len() - 1 {
             if chains[j].compare(&chains[j + 1]) == Ordering::Less {
@@ -325,7 +327,10 @@ pub mod tests {
     #[cfg(feature = "slow_tests")]
     use forest_networks::ChainConfig;
     use fvm_shared::{
-        address::Address, crypto::signature::SignatureType, econ::TokenAmount, message::Message,
+        address::Address,
+        crypto::signature::{Signature, SignatureType},
+        econ::TokenAmount,
+        message::Message,
     };
     #[cfg(feature = "slow_tests")]
     use num_traits::Zero;
@@ -359,6 +364,26 @@ pub mod tests {
         SignedMessage::new_from_parts(umsg, sig).unwrap()
     }
 
+    /// Creates a message with a dummy signature, for tests that never verify
+    /// it.
+    pub fn create_fake_smsg(
+        sequence: u64,
+        gas_limit: i64,
+        gas_fee_cap: u64,
+        gas_premium: u64,
+    ) -> SignedMessage {
+        let umsg = Message {
+            to: Address::new_id(1),
+            from: Address::new_id(2),
+            sequence,
+            gas_limit,
+            gas_fee_cap: TokenAmount::from_atto(gas_fee_cap),
+            gas_premium: TokenAmount::from_atto(gas_premium),
+            ..Message::default()
+        };
+        SignedMessage::new_unchecked(umsg, Signature::new_secp256k1(vec![0; 65]))
+    }
+
     #[tokio::test]
     async fn test_message_pool() {
         let keystore = KeyStore::new(KeyStoreConfig::Memory).unwrap();
@@ -606,8 +631,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -643,8 +667,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -687,8 +710,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -735,8 +757,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -784,8 +805,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -821,8 +841,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -863,8 +882,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -895,8 +913,7 @@ pub mod tests {
             &tma,
             &a1,
             &mset,
-            &TokenAmount::zero(),
-            &ts,
+            &SelectionContext::new(&ts, TokenAmount::zero()),
             &mut chains,
             &chain_config,
         )
@@ -913,4 +930,34 @@ pub mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_msg_chains_per_sender_limit() {
+        let keystore = KeyStore::new(KeyStoreConfig::Memory).unwrap();
+        let mut wallet = Wallet::new(keystore);
+        let a1 = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        let a2 = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        let tma = TestApi::default();
+        tma.set_state_balance_raw(&a1, TokenAmount::from_whole(1));
+        let gas_limit = 6955002;
+
+        let ts = Tipset::from(mock_block(1, 1));
+        let mut mset = HashMap::new();
+        for i in 0..50 {
+            let msg = create_smsg(&a2, &a1, wallet.borrow_mut(), i, gas_limit, 1 + i);
+            mset.insert(i, msg);
+        }
+
+        let mut ctx = SelectionContext::new(&ts, TokenAmount::zero());
+        ctx.max_per_sender = 10;
+        let mut chains = Chains::new();
+        create_message_chains(&tma, &a1, &mset, &ctx, &mut chains, &ChainConfig::default())
+            .unwrap();
+
+        let selected: Vec<u64> = chains
+            .iter_valid()
+            .flat_map(|chain| chain.msgs.iter().map(|m| m.sequence()))
+            .collect();
+        assert_eq!(selected, (0..10).collect::<Vec<_>>());
+    }
 }
@@ -353,7 +353,7 @@ where
             return Ok(());
         }
 
-        msg.verify().map_err(Error::Other)?;
+        msg.verify().map_err(|e| Error::Other(e.to_string()))?;
 
         self.sig_val_cache.lock().put(cid, ());
 
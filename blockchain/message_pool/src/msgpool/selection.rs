@@ -11,43 +11,90 @@ use std::{borrow::BorrowMut, cmp::Ordering, sync::Arc};
 use ahash::{HashMap, HashMapExt};
 use forest_blocks::Tipset;
 use forest_message::{Message, SignedMessage};
-use fvm_shared::{address::Address, econ::TokenAmount};
+use fvm_shared::address::Address;
 use parking_lot::RwLock;
 use rand::{prelude::SliceRandom, thread_rng};
 
 use super::{msg_pool::MessagePool, provider::Provider};
 use crate::{
     add_to_selected_msgs,
-    msg_chain::{create_message_chains, Chains, NodeKey},
+    msg_chain::{create_message_chains, Chains, NodeKey, SelectionContext},
     msg_pool::MsgSet,
-    msgpool::MIN_GAS,
+    msgpool::{MAX_BLOCK_MSGS, MIN_GAS},
     remove_from_selected_msgs, Error,
 };
 
 type Pending = HashMap<Address, HashMap<u64, SignedMessage>>;
 
-// A cap on maximum number of message to include in a block
-const MAX_BLOCK_MSGS: usize = 16000;
 const MAX_BLOCKS: usize = 15;
 
+/// Strategy used to pick the messages of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionMode {
+    /// Merges the chains with the best gas performance until the block is
+    /// full.
+    Greedy,
+    /// Weighs the gas performance of chains by the probability of the block
+    /// they would end up in being included, which pays off for miners with a
+    /// low ticket quality. Like Lotus, chains that don't fit are trimmed in
+    /// place; no combinations of chain prefixes are searched.
+    Optimal,
+}
+
+impl SelectionMode {
+    /// Returns the mode suited to a miner with the given ticket quality.
+    pub fn for_ticket_quality(tq: f64) -> Self {
+        // if the ticket quality is high enough that the first block has higher
+        // probability than any other block, then we don't bother with optimal
+        // selection because the first block will always have higher effective
+        // performance. Otherwise we select message optimally based on effective
+        // performance of chains.
+        if tq > 0.84 {
+            SelectionMode::Greedy
+        } else {
+            SelectionMode::Optimal
+        }
+    }
+}
+
 impl<T> MessagePool<T>
 where
     T: Provider,
 {
     /// Forest employs a sophisticated algorithm for selecting messages
     /// for inclusion from the pool, given the ticket quality of a miner.
-    /// This method selects messages for including in a block.
+    /// This method selects messages for including in a block, using the
+    /// [`SelectionMode`] suited to the ticket quality.
     pub fn select_messages(&self, ts: &Tipset, tq: f64) -> Result<Vec<SignedMessage>, Error> {
+        self.select_messages_with_mode(ts, tq, SelectionMode::for_ticket_quality(tq))
+    }
+
+    /// Same as [`MessagePool::select_messages`], with an explicit selection
+    /// mode.
+    pub fn select_messages_with_mode(
+        &self,
+        ts: &Tipset,
+        tq: f64,
+        mode: SelectionMode,
+    ) -> Result<Vec<SignedMessage>, Error> {
+        let ctx = SelectionContext::with_network(
+            ts,
+            self.api.chain_compute_base_fee(ts)?,
+            &self.chain_config,
+        );
+        self.select_messages_in_context(&ctx, tq, mode)
+    }
+
+    fn select_messages_in_context(
+        &self,
+        ctx: &SelectionContext,
+        tq: f64,
+        mode: SelectionMode,
+    ) -> Result<Vec<SignedMessage>, Error> {
         let cur_ts = self.cur_tipset.lock().clone();
-        // if the ticket quality is high enough that the first block has higher
-        // probability than any other block, then we don't bother with optimal
-        // selection because the first block will always have higher effective
-        // performance. Otherwise we select message optimally based on effective
-        // performance of chains.
-        let mut msgs = if tq > 0.84 {
-            self.select_messages_greedy(&cur_ts, ts)
-        } else {
-            self.select_messages_optimal(&cur_ts, ts, tq)
+        let mut msgs = match mode {
+            SelectionMode::Greedy => self.select_messages_greedy(&cur_ts, ctx),
+            SelectionMode::Optimal => self.select_messages_optimal(&cur_ts, ctx, tq),
         }?;
 
         if msgs.len() > MAX_BLOCK_MSGS {
@@ -60,19 +107,17 @@ where
     fn select_messages_greedy(
         &self,
         cur_ts: &Tipset,
-        ts: &Tipset,
+        ctx: &SelectionContext,
     ) -> Result<Vec<SignedMessage>, Error> {
-        let base_fee = self.api.chain_compute_base_fee(ts)?;
-
         // 0. Load messages from the target tipset; if it is the same as the current
         // tipset in    the mpool, then this is just the pending messages
-        let mut pending = self.get_pending_messages(cur_ts, ts)?;
+        let mut pending = self.get_pending_messages(cur_ts, ctx.ts)?;
 
         if pending.is_empty() {
             return Ok(Vec::new());
         }
         // 0b. Select all priority messages that fit in the block
-        let (result, gas_limit) = self.select_priority_messages(&mut pending, &base_fee, ts)?;
+        let (result, gas_limit) = self.select_priority_messages(&mut pending, ctx)?;
 
         // check if block has been filled
         if gas_limit < MIN_GAS {
@@ -87,36 +132,32 @@ where
                 self.api.as_ref(),
                 &actor,
                 &mset,
-                &base_fee,
-                ts,
+                ctx,
                 &mut chains,
                 &self.chain_config,
             )?;
         }
 
-        let (msgs, _) = merge_and_trim(&mut chains, result, &base_fee, gas_limit, MIN_GAS);
+        let (msgs, _) = merge_and_trim(&mut chains, result, ctx, gas_limit, MIN_GAS);
         Ok(msgs)
     }
 
     fn select_messages_optimal(
         &self,
         cur_ts: &Tipset,
-        target_tipset: &Tipset,
+        ctx: &SelectionContext,
         ticket_quality: f64,
     ) -> Result<Vec<SignedMessage>, Error> {
-        let base_fee = self.api.chain_compute_base_fee(target_tipset)?;
-
         // 0. Load messages from the target tipset; if it is the same as the current
         // tipset in    the mpool, then this is just the pending messages
-        let mut pending = self.get_pending_messages(cur_ts, target_tipset)?;
+        let mut pending = self.get_pending_messages(cur_ts, ctx.ts)?;
 
         if pending.is_empty() {
             return Ok(Vec::new());
         }
 
         // 0b. Select all priority messages that fit in the block
-        let (mut result, mut gas_limit) =
-            self.select_priority_messages(&mut pending, &base_fee, target_tipset)?;
+        let (mut result, mut gas_limit) = self.select_priority_messages(&mut pending, ctx)?;
 
         // check if block has been filled
         if gas_limit < MIN_GAS {
@@ -131,8 +172,7 @@ where
                 self.api.as_ref(),
                 &actor,
                 &mset,
-                &base_fee,
-                target_tipset,
+                ctx,
                 &mut chains,
                 &self.chain_config,
             )?;
@@ -157,7 +197,7 @@ where
         let mut partitions: Vec<Vec<NodeKey>> = vec![vec![]; MAX_BLOCKS];
         let mut i = 0;
         while i < MAX_BLOCKS && next_chain < chains.len() {
-            let mut gas_limit = fvm_shared::BLOCK_GAS_LIMIT;
+            let mut gas_limit = ctx.block_gas_limit;
             while next_chain < chains.len() {
                 let chain_key = chains.key_vec[next_chain];
                 next_chain += 1;
@@ -178,7 +218,7 @@ where
         for i in 0..MAX_BLOCKS {
             for k in &partitions[i] {
                 if let Some(node) = chains.get_mut(*k) {
-                    node.eff_perf = node.gas_perf * block_prob[i];
+                    node.set_eff_perf_with_block_prob(block_prob[i]);
                 }
             }
             eff_chains += partitions[i].len();
@@ -287,7 +327,7 @@ where
         'tail_loop: while gas_limit >= MIN_GAS && last < chains.len() {
             // trim if necessary
             if chains[last].gas_limit > gas_limit {
-                chains.trim_msgs_at(last, gas_limit, &base_fee);
+                chains.trim_msgs_at(last, gas_limit, ctx);
             }
 
             // push down if it hasn't been invalidated
@@ -363,7 +403,7 @@ where
                 }
 
                 // dependencies fit, just trim it
-                chains.trim_msgs_at(i, gas_limit - dep_gas_limit, &base_fee);
+                chains.trim_msgs_at(i, gas_limit - dep_gas_limit, ctx);
                 last += i;
                 continue 'tail_loop;
             }
@@ -418,7 +458,7 @@ where
 
                 // do they fit as it? if it doesn't fit, trim to make it fit if possible
                 if chain_gas_limit > gas_limit {
-                    chains.trim_msgs_at(i, gas_limit - dep_gas_limit, &base_fee);
+                    chains.trim_msgs_at(i, gas_limit - dep_gas_limit, ctx);
 
                     if !chains[i].valid {
                         continue;
@@ -487,11 +527,10 @@ where
     fn select_priority_messages(
         &self,
         pending: &mut Pending,
-        base_fee: &TokenAmount,
-        ts: &Tipset,
+        ctx: &SelectionContext,
     ) -> Result<(Vec<SignedMessage>, i64), Error> {
         let result = Vec::with_capacity(self.config.size_limit_low() as usize);
-        let gas_limit = fvm_shared::BLOCK_GAS_LIMIT;
+        let gas_limit = ctx.block_gas_limit;
         let min_gas = 1298450;
 
         // 1. Get priority actor chains
@@ -505,8 +544,7 @@ where
                     self.api.as_ref(),
                     actor,
                     &mset,
-                    base_fee,
-                    ts,
+                    ctx,
                     &mut chains,
                     &self.chain_config,
                 )?;
@@ -517,13 +555,7 @@ where
             return Ok((Vec::new(), gas_limit));
         }
 
-        Ok(merge_and_trim(
-            &mut chains,
-            result,
-            base_fee,
-            gas_limit,
-            min_gas,
-        ))
+        Ok(merge_and_trim(&mut chains, result, ctx, gas_limit, min_gas))
     }
 }
 
@@ -531,7 +563,7 @@ where
 fn merge_and_trim(
     chains: &mut Chains,
     mut result: Vec<SignedMessage>,
-    base_fee: &TokenAmount,
+    ctx: &SelectionContext,
     gas_limit: i64,
     min_gas: i64,
 ) -> (Vec<SignedMessage>, i64) {
@@ -571,7 +603,7 @@ fn merge_and_trim(
 
     'tail_loop: while gas_limit >= min_gas && last < chain_len {
         // trim, discard negative performing messages
-        chains.trim_msgs_at(last, gas_limit, base_fee);
+        chains.trim_msgs_at(last, gas_limit, ctx);
 
         // push down if it hasn't been invalidated
         let node = &chains[last];
@@ -680,7 +712,7 @@ mod test_selection {
     use forest_db::MemoryDB;
     use forest_key_management::{KeyStore, KeyStoreConfig, Wallet};
     use forest_message::Message;
-    use fvm_shared::crypto::signature::SignatureType;
+    use fvm_shared::{crypto::signature::SignatureType, econ::TokenAmount};
     use tokio::task::JoinSet;
 
     use super::*;
@@ -689,6 +721,7 @@ mod test_selection {
         msgpool::{
             test_provider::{mock_block, TestApi},
             tests::create_smsg,
+            utils::get_gas_reward,
         },
     };
 
@@ -1045,6 +1078,158 @@ mod test_selection {
         }
     }
 
+    #[tokio::test]
+    async fn selection_honours_context_gas_limit() {
+        let mut joinset = JoinSet::new();
+        let mpool = make_test_mpool(&mut joinset);
+
+        let ks1 = KeyStore::new(KeyStoreConfig::Memory).unwrap();
+        let mut w1 = Wallet::new(ks1);
+        let a1 = w1.generate_addr(SignatureType::Secp256k1).unwrap();
+
+        let ks2 = KeyStore::new(KeyStoreConfig::Memory).unwrap();
+        let mut w2 = Wallet::new(ks2);
+        let a2 = w2.generate_addr(SignatureType::Secp256k1).unwrap();
+
+        let b1 = mock_block(1, 1);
+        let ts = Tipset::from(&b1);
+        let api = mpool.api.clone();
+        head_change(
+            api.as_ref(),
+            mpool.bls_sig_cache.as_ref(),
+            Arc::new(mpool.repub_trigger.clone()),
+            mpool.republished.as_ref(),
+            mpool.pending.as_ref(),
+            mpool.cur_tipset.as_ref(),
+            Vec::new(),
+            vec![Tipset::from(b1)],
+        )
+        .await
+        .unwrap();
+
+        api.set_state_balance_raw(&a1, TokenAmount::from_whole(1));
+
+        for i in 0..10 {
+            let m = create_smsg(&a2, &a1, &mut w1, i, TEST_GAS_LIMIT, 1 + i);
+            mpool.add(m).unwrap();
+        }
+
+        let mut ctx = SelectionContext::new(&ts, api.chain_compute_base_fee(&ts).unwrap());
+        ctx.block_gas_limit = 3 * TEST_GAS_LIMIT;
+
+        let cur_ts = mpool.cur_tipset.lock().clone();
+        let msgs = mpool.select_messages_greedy(&cur_ts, &ctx).unwrap();
+
+        assert_eq!(msgs.len(), 3, "only 3 messages fit the context gas limit");
+        for (i, msg) in msgs.iter().enumerate() {
+            assert_eq!(msg.sequence(), i as u64, "nonce should be in order");
+        }
+    }
+
+    #[tokio::test]
+    async fn selection_depends_on_block_gas_limit() {
+        let mut joinset = JoinSet::new();
+        let mpool = make_test_mpool(&mut joinset);
+
+        let mut wallet = Wallet::new(KeyStore::new(KeyStoreConfig::Memory).unwrap());
+        let to = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        let from = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+
+        let b1 = mock_block(1, 1);
+        let ts = Tipset::from(&b1);
+        let api = mpool.api.clone();
+        head_change(
+            api.as_ref(),
+            mpool.bls_sig_cache.as_ref(),
+            Arc::new(mpool.repub_trigger.clone()),
+            mpool.republished.as_ref(),
+            mpool.pending.as_ref(),
+            mpool.cur_tipset.as_ref(),
+            Vec::new(),
+            vec![Tipset::from(b1)],
+        )
+        .await
+        .unwrap();
+
+        api.set_state_balance_raw(&from, TokenAmount::from_whole(1));
+        for i in 0..10 {
+            let m = create_smsg(&to, &from, &mut wallet, i, TEST_GAS_LIMIT, 1 + i);
+            mpool.add(m).unwrap();
+        }
+
+        let network_ctx = SelectionContext::with_network(
+            &ts,
+            api.chain_compute_base_fee(&ts).unwrap(),
+            &mpool.chain_config,
+        );
+        assert_eq!(network_ctx.block_gas_limit, fvm_shared::BLOCK_GAS_LIMIT);
+        let low_ctx = SelectionContext {
+            block_gas_limit: 4 * TEST_GAS_LIMIT,
+            ..network_ctx.clone()
+        };
+
+        let cur_ts = mpool.cur_tipset.lock().clone();
+        let all = mpool.select_messages_greedy(&cur_ts, &network_ctx).unwrap();
+        let limited = mpool.select_messages_greedy(&cur_ts, &low_ctx).unwrap();
+
+        assert_eq!(all.len(), 10);
+        assert_eq!(limited.len(), 4);
+        assert_eq!(limited[..], all[..4]);
+    }
+
+    #[tokio::test]
+    async fn optimal_selection_reward_not_below_greedy() {
+        let mut joinset = JoinSet::new();
+        let mpool = make_test_mpool(&mut joinset);
+
+        let b1 = mock_block(1, 1);
+        let ts = Tipset::from(&b1);
+        let api = mpool.api.clone();
+        head_change(
+            api.as_ref(),
+            mpool.bls_sig_cache.as_ref(),
+            Arc::new(mpool.repub_trigger.clone()),
+            mpool.republished.as_ref(),
+            mpool.pending.as_ref(),
+            mpool.cur_tipset.as_ref(),
+            Vec::new(),
+            vec![Tipset::from(b1)],
+        )
+        .await
+        .unwrap();
+
+        let ks = KeyStore::new(KeyStoreConfig::Memory).unwrap();
+        let mut wallet = Wallet::new(ks);
+        let to = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+        for actor in 0..3u64 {
+            let from = wallet.generate_addr(SignatureType::Secp256k1).unwrap();
+            api.set_state_balance_raw(&from, TokenAmount::from_whole(1));
+            for i in 0..5 {
+                // actors alternate between increasing and decreasing premiums
+                let premium = if actor % 2 == 0 { 1 + i } else { 10 - i };
+                let m = create_smsg(&to, &from, &mut wallet, i, TEST_GAS_LIMIT, premium + actor);
+                mpool.add(m).unwrap();
+            }
+        }
+
+        let mut ctx = SelectionContext::new(&ts, api.chain_compute_base_fee(&ts).unwrap());
+        ctx.block_gas_limit = 7 * TEST_GAS_LIMIT;
+        let total_reward = |mode| {
+            mpool
+                .select_messages_in_context(&ctx, 1.0, mode)
+                .unwrap()
+                .iter()
+                .fold(TokenAmount::default(), |acc, m| {
+                    acc + get_gas_reward(m, &ctx.base_fee)
+                })
+        };
+
+        let greedy = total_reward(SelectionMode::Greedy);
+        let optimal = total_reward(SelectionMode::Optimal);
+        assert!(greedy.is_positive());
+        assert!(optimal >= greedy, "optimal {optimal} < greedy {greedy}");
+    }
+
     #[cfg(feature = "slow_tests")]
     #[tokio::test]
     async fn test_optimal_msg_selection1() {
@@ -8,7 +8,7 @@ use fvm_ipld_encoding::Cbor;
 use fvm_shared::{crypto::signature::Signature, econ::TokenAmount, message::Message};
 use lru::LruCache;
 use num_rational::BigRational;
-use num_traits::ToPrimitive;
+use num_traits::{Signed, ToPrimitive};
 
 use crate::Error;
 
@@ -21,21 +21,34 @@ pub(crate) fn get_base_fee_lower_bound(base_fee: &TokenAmount, factor: i64) -> T
     }
 }
 
-/// Gets the gas reward for the given message.
-pub(crate) fn get_gas_reward(msg: &SignedMessage, base_fee: &TokenAmount) -> TokenAmount {
+/// Gets the gas reward for the given message, i.e. the premium the miner
+/// receives (capped by what is left of the fee cap after the base fee) times
+/// the gas limit.
+pub fn get_gas_reward(msg: &SignedMessage, base_fee: &TokenAmount) -> TokenAmount {
     let mut max_prem = msg.gas_fee_cap() - base_fee;
-    if &max_prem < msg.gas_premium() {
+    if &max_prem > msg.gas_premium() {
         max_prem = msg.gas_premium().clone();
     }
     max_prem * msg.gas_limit()
 }
 
-pub(crate) fn get_gas_perf(gas_reward: &TokenAmount, gas_limit: i64) -> f64 {
+/// Gets the gas performance (reward per block gas limit) for the given reward.
+/// Values that can't be represented as a finite `f64` are saturated to
+/// `f64::MAX`/`f64::MIN`, and a zero gas limit yields `0.0`, so the result
+/// never poisons chain comparisons.
+pub fn get_gas_perf(gas_reward: &TokenAmount, gas_limit: i64) -> f64 {
+    if gas_limit == 0 {
+        return 0.0;
+    }
     let a = BigRational::new(
         gas_reward.atto() * fvm_shared::BLOCK_GAS_LIMIT,
         gas_limit.into(),
     );
-    a.to_f64().unwrap()
+    match a.to_f64() {
+        Some(perf) if !perf.is_nan() => perf.clamp(f64::MIN, f64::MAX),
+        _ if a.is_negative() => f64::MIN,
+        _ => f64::MAX,
+    }
 }
 
 /// Attempt to get a signed message that corresponds to an unsigned message in
@@ -50,3 +63,60 @@ pub(crate) fn recover_sig(
     let smsg = SignedMessage::new_from_parts(msg, val.clone())?;
     Ok(smsg)
 }
+
+#[cfg(test)]
+mod tests {
+    use num_traits::Zero;
+
+    use super::*;
+    use crate::msgpool::tests::create_fake_smsg;
+
+    #[test]
+    fn gas_reward_is_premium_when_fee_cap_allows() {
+        let base_fee = TokenAmount::from_atto(100);
+        // fee_cap - base_fee = 50 > premium
+        let msg = create_fake_smsg(0, 1000, 150, 10);
+        assert_eq!(
+            get_gas_reward(&msg, &base_fee),
+            TokenAmount::from_atto(10 * 1000)
+        );
+    }
+
+    #[test]
+    fn gas_reward_is_capped_by_fee_cap() {
+        let base_fee = TokenAmount::from_atto(100);
+        // fee_cap - base_fee = 5 < premium
+        let msg = create_fake_smsg(0, 1000, 105, 10);
+        assert_eq!(
+            get_gas_reward(&msg, &base_fee),
+            TokenAmount::from_atto(5 * 1000)
+        );
+    }
+
+    #[test]
+    fn gas_reward_with_zero_base_fee() {
+        let msg = create_fake_smsg(0, 1000, 150, 10);
+        assert_eq!(
+            get_gas_reward(&msg, &TokenAmount::zero()),
+            TokenAmount::from_atto(10 * 1000)
+        );
+    }
+
+    #[test]
+    fn zero_gas_limit_has_no_reward_or_perf() {
+        let msg = create_fake_smsg(0, 0, 150, 10);
+        let reward = get_gas_reward(&msg, &TokenAmount::from_atto(100));
+        assert!(reward.is_zero());
+        assert_eq!(get_gas_perf(&reward, 0), 0.0);
+        assert_eq!(get_gas_perf(&TokenAmount::from_atto(10), 0), 0.0);
+    }
+
+    #[test]
+    fn gas_perf_scales_reward_to_block_gas_limit() {
+        let reward = TokenAmount::from_atto(10 * 1000);
+        assert_eq!(
+            get_gas_perf(&reward, 1000),
+            10.0 * fvm_shared::BLOCK_GAS_LIMIT as f64
+        );
+    }
+}
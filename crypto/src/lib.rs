@@ -4,4 +4,7 @@
 mod signer;
 pub mod vrf;
 
-pub use self::{signer::Signer, vrf::*};
+pub use self::{
+    signer::{KeyNotFound, Signer},
+    vrf::*,
+};
@@ -6,6 +6,13 @@ use fvm_shared::{address::Address, crypto::signature::Signature};
 /// Signer is a trait which allows a key implementation to sign data for an
 /// address
 pub trait Signer {
-    /// Function signs any arbitrary data given the [Address].
+    /// Function signs any arbitrary data given the [Address]. Fails with
+    /// [`KeyNotFound`] if the signer holds no key for it.
     fn sign_bytes(&self, data: &[u8], address: &Address) -> Result<Signature, anyhow::Error>;
 }
+
+/// Error returned by [`Signer::sign_bytes`] when the signer holds no key for
+/// the [Address].
+#[derive(Debug, thiserror::Error)]
+#[error("no key found for address {0}")]
+pub struct KeyNotFound(pub Address);
@@ -219,4 +219,44 @@ mod tests {
         };
         assert_eq!(from_str::<TestStruct>(test_json).unwrap(), expected);
     }
+
+    #[test]
+    fn signed_message_vec_roundtrip() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct TestStruct {
+            #[serde(with = "crate::signed_message::json::vec")]
+            messages: Vec<SignedMessage>,
+        }
+
+        let messages: Vec<SignedMessage> = (0..3)
+            .map(|sequence| {
+                SignedMessage::new_unchecked(
+                    Message {
+                        to: Address::new_id(1),
+                        from: Address::new_id(2),
+                        sequence,
+                        ..Message::default()
+                    },
+                    Signature::new_secp256k1(vec![sequence as u8]),
+                )
+            })
+            .collect();
+        let test_struct = TestStruct { messages };
+
+        let json: serde_json::Value = serde_json::to_value(&test_struct).unwrap();
+        let array = json["messages"].as_array().unwrap();
+        assert_eq!(array.len(), 3);
+        for (i, entry) in array.iter().enumerate() {
+            assert_eq!(
+                entry,
+                &serde_json::to_value(SignedMessageJsonRef(&test_struct.messages[i])).unwrap()
+            );
+            assert_eq!(entry["Message"]["Nonce"], i);
+        }
+
+        assert_eq!(
+            serde_json::from_value::<TestStruct>(json).unwrap(),
+            test_struct
+        );
+    }
 }
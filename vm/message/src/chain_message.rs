@@ -25,6 +25,12 @@ impl ChainMessage {
             Self::Signed(sm) => sm.message(),
         }
     }
+
+    /// Returns the CID of the message, same as [`Cbor::cid`] and
+    /// [`MessageTrait::cid`].
+    pub fn cid(&self) -> Result<Cid, Error> {
+        Cbor::cid(self)
+    }
 }
 
 impl MessageTrait for ChainMessage {
@@ -114,6 +120,10 @@ impl MessageTrait for ChainMessage {
             Self::Unsigned(t) => t.gas_premium = prem,
         }
     }
+
+    fn cid(&self) -> Result<Cid, Error> {
+        Cbor::cid(self)
+    }
 }
 
 impl Cbor for ChainMessage {
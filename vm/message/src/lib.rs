@@ -6,9 +6,12 @@ pub mod message;
 pub mod signed_message;
 
 pub use chain_message::ChainMessage;
-use fvm_ipld_encoding::RawBytes;
+use cid::Cid;
+use fvm_ipld_encoding::{Error as CborError, RawBytes};
 use fvm_shared::{address::Address, econ::TokenAmount, MethodNum};
-pub use signed_message::SignedMessage;
+pub use signed_message::{
+    CryptoError, EncodingError, MessageValidationError, SignedMessage, SignedMessageBuilder,
+};
 
 /// Message interface to interact with Signed and unsigned messages in a generic
 /// context.
@@ -41,4 +44,9 @@ pub trait Message {
     fn set_gas_fee_cap(&mut self, cap: TokenAmount);
     /// sets the gas premium.
     fn set_gas_premium(&mut self, prem: TokenAmount);
+    /// Returns the `Blake2b256` CID of the message's CBOR encoding. A signed
+    /// message is encoded with its signature, so its CID differs from the one
+    /// of the unsigned message it wraps. BLS messages are the exception, as
+    /// they are encoded without their signature.
+    fn cid(&self) -> Result<Cid, CborError>;
 }
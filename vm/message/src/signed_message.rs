@@ -1,10 +1,14 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
+use cid::Cid;
+use forest_crypto::{KeyNotFound, Signer};
 use forest_encoding::tuple::*;
-use fvm_ipld_encoding::{to_vec, Cbor, Error as CborError, RawBytes};
+use forest_shim::version::NetworkVersion;
+use fvm::gas::Gas;
+use fvm_ipld_encoding::{from_slice, to_vec, Cbor, Error as CborError, RawBytes};
 use fvm_shared::{
-    address::Address,
+    address::{Address, Protocol},
     crypto::signature::{Signature, SignatureType},
     econ::TokenAmount,
     message::Message,
@@ -12,6 +16,50 @@ use fvm_shared::{
 };
 
 use super::Message as MessageTrait;
+use crate::message::valid_for_block_inclusion;
+
+/// Reasons for [`SignedMessage::valid_for_block`] to reject a message.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MessageValidationError {
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("invalid message: {0}")]
+    InvalidMessage(String),
+    #[error("message size {size} exceeds the limit of {max_size} bytes")]
+    TooLarge { size: usize, max_size: usize },
+    #[error("failed to encode message: {0}")]
+    Encoding(String),
+}
+
+/// Errors raised while verifying the signature of a [`SignedMessage`].
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("{sig_type:?} signature cannot be produced by a {protocol:?} address")]
+    SignatureTypeMismatch {
+        protocol: Protocol,
+        sig_type: SignatureType,
+    },
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("failed to encode message: {0}")]
+    Encoding(String),
+    #[error("no key found for address {0}")]
+    KeyNotFound(Address),
+    #[error("failed to sign message: {0}")]
+    Signing(String),
+}
+
+/// Errors raised while decoding a [`SignedMessage`] framed by
+/// [`SignedMessage::to_bytes_framed`].
+#[derive(Debug, thiserror::Error)]
+pub enum EncodingError {
+    #[error("framed message is missing its length prefix")]
+    MissingLengthPrefix,
+    #[error("framed message is truncated: expected {expected} bytes, got {actual}")]
+    Truncated { expected: usize, actual: usize },
+    #[error(transparent)]
+    Cbor(#[from] CborError),
+}
 
 /// Represents a wrapped message with signature bytes.
 #[derive(PartialEq, Clone, Debug, Serialize_tuple, Deserialize_tuple, Hash, Eq)]
@@ -21,13 +69,33 @@ pub struct SignedMessage {
 }
 
 impl SignedMessage {
+    /// Starts building an unsigned message that is signed with
+    /// [`SignedMessageBuilder::sign_with`].
+    pub fn builder() -> SignedMessageBuilder {
+        SignedMessageBuilder::default()
+    }
+
+    /// Signs the message with the key of its `from` address.
+    pub fn new<S: Signer>(message: Message, signer: &S) -> Result<SignedMessage, CryptoError> {
+        let bz = message
+            .cid()
+            .map_err(|e| CryptoError::Encoding(e.to_string()))?
+            .to_bytes();
+        let signature = signer.sign_bytes(&bz, &message.from).map_err(|e| {
+            match e.downcast_ref::<KeyNotFound>() {
+                Some(KeyNotFound(address)) => CryptoError::KeyNotFound(*address),
+                None => CryptoError::Signing(e.to_string()),
+            }
+        })?;
+        Ok(SignedMessage { message, signature })
+    }
+
     /// Generate a new signed message from fields.
     /// The signature will be verified.
     pub fn new_from_parts(message: Message, signature: Signature) -> anyhow::Result<SignedMessage> {
-        signature
-            .verify(&message.cid()?.to_bytes(), &message.from)
-            .map_err(anyhow::Error::msg)?;
-        Ok(SignedMessage { message, signature })
+        let smsg = SignedMessage { message, signature };
+        smsg.verify()?;
+        Ok(smsg)
     }
 
     /// Generate a new signed message from fields.
@@ -36,6 +104,13 @@ impl SignedMessage {
         SignedMessage { message, signature }
     }
 
+    /// Returns the CID of the message, same as [`Cbor::cid`] and
+    /// [`MessageTrait::cid`]. Defined here so that callers with both traits in
+    /// scope don't have to pick one.
+    pub fn cid(&self) -> Result<Cid, CborError> {
+        Cbor::cid(self)
+    }
+
     /// Returns reference to the unsigned message.
     pub fn message(&self) -> &Message {
         &self.message
@@ -61,10 +136,161 @@ impl SignedMessage {
         self.signature.signature_type() == SignatureType::Secp256k1
     }
 
+    /// Checks that the message can be valid on a network at the given version.
+    /// Messages don't carry a chain id, so this only checks the message
+    /// version, which is 0 at every network version so far. Replay protection
+    /// across chains belongs here once messages carry one.
+    pub fn matches_network(&self, _network_version: NetworkVersion) -> bool {
+        self.message.version == 0
+    }
+
+    /// Replaces the gas fee cap and gas premium of the unsigned message and
+    /// signs it again, e.g. to replace a pending message by fee.
+    pub fn resign_with_gas<S: Signer>(
+        self,
+        signer: &S,
+        gas_fee_cap: TokenAmount,
+        gas_premium: TokenAmount,
+    ) -> Result<SignedMessage, CryptoError> {
+        let mut message = self.message;
+        message.gas_fee_cap = gas_fee_cap;
+        message.gas_premium = gas_premium;
+        SignedMessage::new(message, signer)
+    }
+
+    /// Encodes the message and its signature as CBOR, prefixed with the length
+    /// of the encoding as a 4-byte big-endian integer.
+    pub fn to_bytes_framed(&self) -> Result<Vec<u8>, CborError> {
+        let bz = to_vec(self)?;
+        let mut framed = Vec::with_capacity(bz.len() + 4);
+        framed.extend_from_slice(&(bz.len() as u32).to_be_bytes());
+        framed.extend_from_slice(&bz);
+        Ok(framed)
+    }
+
+    /// Decodes a message framed by [`SignedMessage::to_bytes_framed`] from the
+    /// start of the buffer. Returns the message and the number of bytes
+    /// consumed, so that consecutive frames can be read from a stream.
+    pub fn from_bytes_framed(bz: &[u8]) -> Result<(SignedMessage, usize), EncodingError> {
+        let len_bytes: [u8; 4] = bz
+            .get(..4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(EncodingError::MissingLengthPrefix)?;
+        let end = 4 + u32::from_be_bytes(len_bytes) as usize;
+        let body = bz.get(4..end).ok_or(EncodingError::Truncated {
+            expected: end,
+            actual: bz.len(),
+        })?;
+        Ok((from_slice(body)?, end))
+    }
+
+    /// Checks if both signed messages wrap the same unsigned message,
+    /// regardless of their signatures.
+    pub fn same_message(&self, other: &SignedMessage) -> bool {
+        self.message == other.message
+    }
+
+    /// Checks everything a block producer needs to know about a message before
+    /// including it: the semantic checks of [`valid_for_block_inclusion`], an
+    /// on-chain size of at most `max_size` bytes and a valid signature.
+    /// The signature is checked last as it is the most expensive check.
+    pub fn valid_for_block(
+        &self,
+        min_gas: Gas,
+        version: NetworkVersion,
+        max_size: usize,
+    ) -> Result<(), MessageValidationError> {
+        valid_for_block_inclusion(&self.message, min_gas, version)
+            .map_err(|e| MessageValidationError::InvalidMessage(e.to_string()))?;
+        let size = self
+            .marshal_cbor()
+            .map_err(|e| MessageValidationError::Encoding(e.to_string()))?
+            .len();
+        if size > max_size {
+            return Err(MessageValidationError::TooLarge { size, max_size });
+        }
+        self.verify().map_err(|e| match e {
+            CryptoError::Encoding(e) => MessageValidationError::Encoding(e),
+            e => MessageValidationError::InvalidSignature(e.to_string()),
+        })
+    }
+
     /// Verifies that the from address of the message generated the signature.
-    pub fn verify(&self) -> Result<(), String> {
+    /// Signatures of a type the address protocol cannot produce are rejected
+    /// without running any cryptographic check.
+    pub fn verify(&self) -> Result<(), CryptoError> {
+        let protocol = self.from().protocol();
+        let sig_type = self.signature.signature_type();
+        match (protocol, sig_type) {
+            (Protocol::BLS, SignatureType::BLS)
+            | (Protocol::Secp256k1, SignatureType::Secp256k1) => {}
+            _ => return Err(CryptoError::SignatureTypeMismatch { protocol, sig_type }),
+        }
+        let cid = self
+            .message
+            .cid()
+            .map_err(|e| CryptoError::Encoding(e.to_string()))?;
         self.signature
-            .verify(&self.message.cid().unwrap().to_bytes(), self.from())
+            .verify(&cid.to_bytes(), self.from())
+            .map_err(CryptoError::InvalidSignature)
+    }
+}
+
+/// Builds an unsigned message field by field and signs it in one go.
+#[derive(Clone, Debug, Default)]
+pub struct SignedMessageBuilder {
+    message: Message,
+}
+
+impl SignedMessageBuilder {
+    pub fn to(mut self, to: Address) -> Self {
+        self.message.to = to;
+        self
+    }
+
+    pub fn from(mut self, from: Address) -> Self {
+        self.message.from = from;
+        self
+    }
+
+    pub fn sequence(mut self, sequence: u64) -> Self {
+        self.message.sequence = sequence;
+        self
+    }
+
+    pub fn value(mut self, value: TokenAmount) -> Self {
+        self.message.value = value;
+        self
+    }
+
+    pub fn method_num(mut self, method_num: MethodNum) -> Self {
+        self.message.method_num = method_num;
+        self
+    }
+
+    pub fn params(mut self, params: RawBytes) -> Self {
+        self.message.params = params;
+        self
+    }
+
+    pub fn gas_limit(mut self, gas_limit: i64) -> Self {
+        self.message.gas_limit = gas_limit;
+        self
+    }
+
+    pub fn gas_fee_cap(mut self, gas_fee_cap: TokenAmount) -> Self {
+        self.message.gas_fee_cap = gas_fee_cap;
+        self
+    }
+
+    pub fn gas_premium(mut self, gas_premium: TokenAmount) -> Self {
+        self.message.gas_premium = gas_premium;
+        self
+    }
+
+    /// Signs the built message with the key of its `from` address.
+    pub fn sign_with<S: Signer>(self, signer: &S) -> Result<SignedMessage, CryptoError> {
+        SignedMessage::new(self.message, signer)
     }
 }
 
@@ -113,6 +339,10 @@ impl MessageTrait for SignedMessage {
     fn set_gas_premium(&mut self, prem: TokenAmount) {
         self.message.gas_premium = prem;
     }
+
+    fn cid(&self) -> Result<Cid, CborError> {
+        Cbor::cid(self)
+    }
 }
 
 impl Cbor for SignedMessage {
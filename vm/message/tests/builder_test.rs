@@ -1,9 +1,14 @@
 // Copyright 2019-2023 ChainSafe Systems
 // SPDX-License-Identifier: Apache-2.0, MIT
 
-use forest_message::SignedMessage;
-use fvm_shared::{address::Address, crypto::signature::Signature, message::Message};
+mod signer;
+
+use forest_message::{Message as MessageTrait, SignedMessage};
+use fvm_shared::{
+    address::Address, crypto::signature::Signature, econ::TokenAmount, message::Message,
+};
 use rand::{rngs::OsRng, RngCore};
+use signer::KeySigner;
 
 #[test]
 fn generate_signed_message() {
@@ -22,3 +27,23 @@ fn generate_signed_message() {
     assert_eq!(signed_msg.message(), &msg);
     assert_eq!(signed_msg.signature(), &Signature::new_secp256k1(dummy_sig));
 }
+
+#[test]
+fn build_and_sign_signed_message() {
+    let signer = KeySigner::generate();
+    let smsg = SignedMessage::builder()
+        .to(Address::new_id(1))
+        .from(signer.address)
+        .sequence(3)
+        .value(TokenAmount::from_atto(10))
+        .gas_limit(1000)
+        .gas_fee_cap(TokenAmount::from_atto(100))
+        .gas_premium(TokenAmount::from_atto(10))
+        .sign_with(&signer)
+        .unwrap();
+
+    smsg.verify().unwrap();
+    assert_eq!(smsg.from(), &signer.address);
+    assert_eq!(smsg.sequence(), 3);
+    assert_eq!(smsg.gas_limit(), 1000);
+}
@@ -0,0 +1,62 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+mod signer;
+
+use forest_message::{CryptoError, Message as MessageTrait, SignedMessage};
+use fvm_shared::{address::Address, econ::TokenAmount, message::Message};
+use signer::KeySigner;
+
+#[test]
+fn resign_with_gas_updates_fees_and_signature() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(7);
+
+    let resigned = smsg
+        .clone()
+        .resign_with_gas(
+            &signer,
+            TokenAmount::from_atto(200),
+            TokenAmount::from_atto(20),
+        )
+        .unwrap();
+
+    resigned.verify().unwrap();
+    assert_ne!(resigned.signature(), smsg.signature());
+    assert_eq!(resigned.gas_fee_cap(), &TokenAmount::from_atto(200));
+    assert_eq!(resigned.gas_premium(), &TokenAmount::from_atto(20));
+    assert_eq!(resigned.sequence(), smsg.sequence());
+    assert_eq!(resigned.to(), smsg.to());
+    assert_eq!(resigned.value(), smsg.value());
+
+    let other = KeySigner::generate();
+    assert_eq!(
+        smsg.resign_with_gas(
+            &other,
+            TokenAmount::from_atto(200),
+            TokenAmount::from_atto(20)
+        ),
+        Err(CryptoError::KeyNotFound(signer.address))
+    );
+}
+
+#[test]
+fn new_reports_missing_key() {
+    let signer = KeySigner::generate();
+    let msg = Message {
+        to: Address::new_id(1),
+        from: Address::new_id(2),
+        ..Message::default()
+    };
+
+    assert_eq!(
+        SignedMessage::new(msg.clone(), &signer),
+        Err(CryptoError::KeyNotFound(msg.from))
+    );
+
+    let msg = Message {
+        from: signer.address,
+        ..msg
+    };
+    SignedMessage::new(msg, &signer).unwrap().verify().unwrap();
+}
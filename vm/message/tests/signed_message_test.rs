@@ -0,0 +1,130 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+mod signer;
+
+use cid::Cid;
+use forest_message::{
+    ChainMessage, CryptoError, EncodingError, Message as MessageTrait, SignedMessage,
+};
+use forest_shim::version::NetworkVersion;
+use fvm_ipld_encoding::Cbor;
+use fvm_shared::{
+    address::{Address, Protocol},
+    crypto::signature::{Signature, SignatureType},
+    message::Message,
+};
+use signer::KeySigner;
+
+#[test]
+fn framed_round_trip() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(1);
+    let framed = smsg.to_bytes_framed().unwrap();
+
+    let (decoded, consumed) = SignedMessage::from_bytes_framed(&framed).unwrap();
+    assert_eq!(decoded, smsg);
+    assert_eq!(consumed, framed.len());
+}
+
+#[test]
+fn framed_decode_concatenated() {
+    let signer = KeySigner::generate();
+    let (first, second) = (signer.signed_message(1), signer.signed_message(2));
+    let mut buf = first.to_bytes_framed().unwrap();
+    buf.extend(second.to_bytes_framed().unwrap());
+
+    let (decoded, consumed) = SignedMessage::from_bytes_framed(&buf).unwrap();
+    assert_eq!(decoded, first);
+    let (decoded, rest) = SignedMessage::from_bytes_framed(&buf[consumed..]).unwrap();
+    assert_eq!(decoded, second);
+    assert_eq!(consumed + rest, buf.len());
+
+    assert!(matches!(
+        SignedMessage::from_bytes_framed(&buf[..consumed - 1]),
+        Err(EncodingError::Truncated { expected, actual }) if expected == consumed && actual == consumed - 1
+    ));
+    assert!(matches!(
+        SignedMessage::from_bytes_framed(&buf[..3]),
+        Err(EncodingError::MissingLengthPrefix)
+    ));
+}
+
+#[test]
+fn same_message_ignores_signature() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(1);
+    let resigned = SignedMessage::new_unchecked(
+        smsg.message().clone(),
+        Signature::new_secp256k1(vec![9; 65]),
+    );
+
+    assert!(smsg.same_message(&resigned));
+    assert_ne!(smsg, resigned);
+    assert!(!smsg.same_message(&signer.signed_message(2)));
+}
+
+#[test]
+fn verify_rejects_signature_type_mismatch() {
+    let bls_from = Address::new_bls(&[1; 48]).unwrap();
+    let msg = Message {
+        to: Address::new_id(1),
+        from: bls_from,
+        ..Message::default()
+    };
+    let smsg = SignedMessage::new_unchecked(msg.clone(), Signature::new_secp256k1(vec![0; 65]));
+    assert_eq!(
+        smsg.verify(),
+        Err(CryptoError::SignatureTypeMismatch {
+            protocol: Protocol::BLS,
+            sig_type: SignatureType::Secp256k1,
+        })
+    );
+    assert!(SignedMessage::new_from_parts(msg, Signature::new_secp256k1(vec![0; 65])).is_err());
+
+    let secp_from = Address::new_secp256k1(&[4; 65]).unwrap();
+    let msg = Message {
+        to: Address::new_id(1),
+        from: secp_from,
+        ..Message::default()
+    };
+    let smsg = SignedMessage::new_unchecked(msg, Signature::new_bls(vec![0; 96]));
+    assert_eq!(
+        smsg.verify(),
+        Err(CryptoError::SignatureTypeMismatch {
+            protocol: Protocol::Secp256k1,
+            sig_type: SignatureType::BLS,
+        })
+    );
+}
+
+fn trait_cid<M: MessageTrait>(msg: &M) -> Cid {
+    msg.cid().unwrap()
+}
+
+#[test]
+fn message_trait_cid_is_stable() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(1);
+    let signed = ChainMessage::Signed(smsg.clone());
+    let unsigned = ChainMessage::Unsigned(smsg.message().clone());
+
+    assert_eq!(trait_cid(&smsg), trait_cid(&signer.signed_message(1)));
+    assert_eq!(trait_cid(&smsg), Cbor::cid(&smsg).unwrap());
+    assert_eq!(trait_cid(&signed), trait_cid(&smsg));
+    assert_eq!(trait_cid(&unsigned), smsg.message().cid().unwrap());
+    assert_ne!(trait_cid(&signed), trait_cid(&unsigned));
+}
+
+#[test]
+fn matches_network_checks_message_version() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(1);
+    assert!(smsg.matches_network(NetworkVersion::V0));
+    assert!(smsg.matches_network(NetworkVersion::V16));
+
+    let mut msg = smsg.message().clone();
+    msg.version = 1;
+    let smsg = SignedMessage::new_unchecked(msg, smsg.signature().clone());
+    assert!(!smsg.matches_network(NetworkVersion::V16));
+}
@@ -0,0 +1,68 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+// not every test crate uses every helper
+#![allow(dead_code)]
+
+use forest_crypto::{KeyNotFound, Signer};
+use forest_encoding::blake2b_256;
+use forest_message::SignedMessage;
+use fvm_shared::{
+    address::Address, crypto::signature::Signature, econ::TokenAmount, message::Message,
+};
+use libsecp256k1::{Message as SecpMessage, PublicKey, SecretKey};
+use rand::rngs::OsRng;
+
+/// In-memory `secp256k1` signer holding a single key.
+pub struct KeySigner {
+    key: SecretKey,
+    pub address: Address,
+}
+
+impl KeySigner {
+    pub fn generate() -> Self {
+        let key = SecretKey::random(&mut OsRng);
+        let address = Address::new_secp256k1(&PublicKey::from_secret_key(&key).serialize())
+            .expect("valid public key");
+        Self { key, address }
+    }
+
+    /// Returns a message from the signer's address that passes the block
+    /// inclusion checks.
+    pub fn message(&self, sequence: u64) -> Message {
+        Message {
+            to: Address::new_id(1),
+            from: self.address,
+            sequence,
+            value: TokenAmount::from_atto(42),
+            gas_limit: 1000,
+            gas_fee_cap: TokenAmount::from_atto(100),
+            gas_premium: TokenAmount::from_atto(10),
+            ..Message::default()
+        }
+    }
+
+    /// Signs `msg`, which must be sent from the signer's address.
+    pub fn sign(&self, msg: Message) -> SignedMessage {
+        SignedMessage::new(msg, self).unwrap()
+    }
+
+    /// Signs [`KeySigner::message`] with the given sequence.
+    pub fn signed_message(&self, sequence: u64) -> SignedMessage {
+        self.sign(self.message(sequence))
+    }
+}
+
+impl Signer for KeySigner {
+    fn sign_bytes(&self, data: &[u8], address: &Address) -> Result<Signature, anyhow::Error> {
+        if address != &self.address {
+            return Err(KeyNotFound(*address).into());
+        }
+        let message = SecpMessage::parse(&blake2b_256(data));
+        let (sig, recovery_id) = libsecp256k1::sign(&message, &self.key);
+        let mut bytes = [0; 65];
+        bytes[..64].copy_from_slice(&sig.serialize());
+        bytes[64] = recovery_id.serialize();
+        Ok(Signature::new_secp256k1(bytes.to_vec()))
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2019-2023 ChainSafe Systems
+// SPDX-License-Identifier: Apache-2.0, MIT
+
+mod signer;
+
+use forest_message::{MessageValidationError, SignedMessage};
+use forest_shim::version::NetworkVersion;
+use fvm::gas::Gas;
+use fvm_ipld_encoding::Cbor;
+use fvm_shared::{crypto::signature::Signature, econ::TokenAmount};
+use signer::KeySigner;
+
+const MAX_SIZE: usize = 32 * 1024;
+const VERSION: NetworkVersion = NetworkVersion::V18;
+
+fn min_gas() -> Gas {
+    Gas::new(100)
+}
+
+#[test]
+fn valid_message_passes() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(0);
+
+    assert_eq!(smsg.valid_for_block(min_gas(), VERSION, MAX_SIZE), Ok(()));
+}
+
+#[test]
+fn invalid_signature_is_rejected() {
+    let signer = KeySigner::generate();
+    let smsg =
+        SignedMessage::new_unchecked(signer.message(0), Signature::new_secp256k1(vec![0; 65]));
+
+    assert!(matches!(
+        smsg.valid_for_block(min_gas(), VERSION, MAX_SIZE),
+        Err(MessageValidationError::InvalidSignature(_))
+    ));
+}
+
+#[test]
+fn negative_value_is_rejected() {
+    let signer = KeySigner::generate();
+    let mut msg = signer.message(0);
+    msg.value = TokenAmount::from_atto(-1);
+
+    assert!(matches!(
+        signer
+            .sign(msg)
+            .valid_for_block(min_gas(), VERSION, MAX_SIZE),
+        Err(MessageValidationError::InvalidMessage(_))
+    ));
+}
+
+#[test]
+fn gas_limit_below_min_gas_is_rejected() {
+    let signer = KeySigner::generate();
+    let mut msg = signer.message(0);
+    msg.gas_limit = 99;
+
+    assert!(matches!(
+        signer
+            .sign(msg)
+            .valid_for_block(min_gas(), VERSION, MAX_SIZE),
+        Err(MessageValidationError::InvalidMessage(_))
+    ));
+}
+
+#[test]
+fn negative_gas_fee_is_rejected() {
+    let signer = KeySigner::generate();
+    let mut msg = signer.message(0);
+    msg.gas_premium = TokenAmount::from_atto(-1);
+
+    assert!(matches!(
+        signer
+            .sign(msg)
+            .valid_for_block(min_gas(), VERSION, MAX_SIZE),
+        Err(MessageValidationError::InvalidMessage(_))
+    ));
+}
+
+#[test]
+fn oversized_message_is_rejected() {
+    let signer = KeySigner::generate();
+    let smsg = signer.signed_message(0);
+    let size = smsg.marshal_cbor().unwrap().len();
+
+    assert_eq!(
+        smsg.valid_for_block(min_gas(), VERSION, size - 1),
+        Err(MessageValidationError::TooLarge {
+            size,
+            max_size: size - 1
+        })
+    );
+}
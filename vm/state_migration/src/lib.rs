@@ -4,23 +4,30 @@
 //! Common code that's shared across all migration code.
 //! Each network upgrade / state migration code lives in their own module.
 
-use std::sync::Arc;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
 
 use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use cid::Cid;
+use crossbeam_channel::{Receiver, Sender};
 use forest_shim::{
     state_tree::{ActorState, StateTree},
     Inner,
 };
 use fvm_ipld_blockstore::Blockstore;
-use fvm_shared::{address::Address, clock::ChainEpoch, econ::TokenAmount};
+use fvm_ipld_car::CarHeader;
+use fvm_ipld_hamt::Hamt;
+use fvm_shared::{address::Address, clock::ChainEpoch, econ::TokenAmount, state::StateTreeVersion};
 use rayon::ThreadPoolBuildError;
+use serde::{de::DeserializeOwned, Serialize};
 
 // pub mod nv12;
 
 pub const ACTORS_COUNT: usize = 11;
 
-pub type Migrator<BS> = Arc<dyn ActorMigration<BS> + Send + Sync>;
+pub type Migrator<BS> = Arc<dyn ActorMigration<MigrationStore<BS>> + Send + Sync>;
 pub type MigrationResult<T> = Result<T, MigrationError>;
 
 #[derive(thiserror::Error, Debug)]
@@ -46,6 +53,14 @@ pub enum MigrationError {
     IncompleteMigrationSpec(usize),
     #[error("Thread pool creation failed: {0}")]
     ThreadPoolCreation(ThreadPoolBuildError),
+    #[error("Failed exporting source actor state: {0}")]
+    Export(String),
+    #[error("State migration failed for actor {address} with code {code}: {source}")]
+    InActor {
+        address: Address,
+        code: Cid,
+        source: Box<MigrationError>,
+    },
     #[error("Migration failed")]
     Other,
 }
@@ -53,6 +68,7 @@ pub enum MigrationError {
 pub struct StateMigration<BS> {
     migrations: HashMap<Cid, Migrator<BS>>,
     deferred_code_ids: HashSet<Cid>,
+    channel_capacity: Option<usize>,
 }
 
 impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
@@ -61,9 +77,17 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
         Self {
             migrations: HashMap::new(),
             deferred_code_ids: HashSet::new(),
+            channel_capacity: None,
         }
     }
 
+    /// Sets the capacity of the channels feeding actors to the migration
+    /// workers and collecting their output, which defaults to half the number
+    /// of CPUs. The state tree walk blocks while they are full.
+    pub fn set_channel_capacity(&mut self, capacity: usize) {
+        self.channel_capacity = Some(capacity);
+    }
+
     pub fn add_migrator(&mut self, prior_cid: Cid, migrator: Migrator<BS>) {
         self.migrations.insert(prior_cid, migrator);
     }
@@ -73,8 +97,112 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
         store: Arc<BS>,
         prior_epoch: ChainEpoch,
         actors_in: StateTree<BS>,
-        mut actors_out: StateTree<BS>,
+        actors_out: StateTree<BS>,
     ) -> MigrationResult<Cid> {
+        self.run_migration(
+            MigrationStore::new(store),
+            prior_epoch,
+            actors_in,
+            actors_out,
+            false,
+            None,
+        )
+        .map(|outcome| outcome.root)
+    }
+
+    /// Same as [`StateMigration::migrate_state_tree`] for the state tree at
+    /// `state_root` of `store`, but also writes every block read during the
+    /// migration to `export` as a CARv1 file rooted at `state_root`, so that
+    /// the pre-migration state can be archived. That is the source state tree,
+    /// the head of every source actor and whatever the actor migrators read.
+    pub fn migrate_state_tree_with_export<W: Write + Send>(
+        &mut self,
+        store: Arc<BS>,
+        prior_epoch: ChainEpoch,
+        state_root: &Cid,
+        actors_out: StateTree<BS>,
+        export: &mut W,
+    ) -> MigrationResult<Cid> {
+        write_car_header(state_root, export)?;
+        let (store, blocks) = MigrationStore::exporting(store);
+        let actors_in = StateTree::new_from_root(store.clone(), state_root)
+            .map_err(|e| MigrationError::StateTreeCreation(e.to_string()))?;
+        self.run_migration(
+            store,
+            prior_epoch,
+            actors_in,
+            actors_out,
+            false,
+            Some(Exporter::new(blocks, export)),
+        )
+        .map(|outcome| outcome.root)
+    }
+
+    /// Same as [`StateMigration::migrate_state_tree`], but also returns the
+    /// code CID transitions of every actor whose code changed, e.g. for audit
+    /// logs. Actors keeping their code are omitted.
+    pub fn migrate_state_tree_with_diff(
+        &mut self,
+        store: Arc<BS>,
+        prior_epoch: ChainEpoch,
+        actors_in: StateTree<BS>,
+        actors_out: StateTree<BS>,
+    ) -> MigrationResult<(Cid, Vec<CodeChange>)> {
+        self.run_migration(
+            MigrationStore::new(store),
+            prior_epoch,
+            actors_in,
+            actors_out,
+            true,
+            None,
+        )
+        .map(|outcome| (outcome.root, outcome.diff))
+    }
+
+    /// Migrates the state tree at `state_root` of `store` without writing
+    /// anything to it. Only the CIDs and sizes of the blocks written by the
+    /// actor migrators and the new state tree are kept, so migrators can't
+    /// read back what they wrote. Returns how many actors the new tree holds
+    /// and how many bytes the migration would write.
+    pub fn dry_run(
+        &mut self,
+        store: Arc<BS>,
+        prior_epoch: ChainEpoch,
+        state_root: &Cid,
+        version: StateTreeVersion,
+    ) -> MigrationResult<DryRunEstimate> {
+        let store = MigrationStore::dry_run(store);
+        let actors_in = StateTree::new_from_root(store.clone(), state_root)
+            .map_err(|e| MigrationError::StateTreeCreation(e.to_string()))?;
+        let actors_out = StateTree::new(store.clone(), version)
+            .map_err(|e| MigrationError::StateTreeCreation(e.to_string()))?;
+        let outcome = self.run_migration(
+            store.clone(),
+            prior_epoch,
+            actors_in,
+            actors_out,
+            false,
+            None,
+        )?;
+        Ok(DryRunEstimate {
+            actor_count: outcome.actor_count,
+            estimated_bytes: store.written_bytes(),
+        })
+    }
+
+    fn run_migration<IS, OS>(
+        &mut self,
+        store: MigrationStore<BS>,
+        prior_epoch: ChainEpoch,
+        actors_in: StateTree<IS>,
+        mut actors_out: StateTree<OS>,
+        record_diff: bool,
+        mut export: Option<Exporter<'_>>,
+    ) -> MigrationResult<MigrationOutcome>
+    where
+        IS: Blockstore + Send,
+        OS: Blockstore + Send,
+    {
         if self.migrations.len() + self.deferred_code_ids.len() != ACTORS_COUNT {
             return Err(MigrationError::IncompleteMigrationSpec(
                 self.migrations.len(),
@@ -82,7 +210,7 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
         }
 
         let cpus = num_cpus::get();
-        let chan_size = cpus / 2;
+        let chan_size = self.chan_size();
 
         log::info!(
             "Using {} CPUs for migration and channel size of {}",
@@ -90,22 +218,50 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
             chan_size
         );
 
+        let store = Arc::new(store);
+        let threads = migration_threads();
         let pool = rayon::ThreadPoolBuilder::new()
             .thread_name(|id| format!("nv12 migration thread: {id}"))
-            .num_threads(cpus)
+            .num_threads(threads)
             .build()
             .map_err(MigrationError::ThreadPoolCreation)?;
 
+        // Actors are streamed from the input tree into bounded channels and
+        // picked up by a fixed number of workers, so at most `2 * chan_size +
+        // threads` actors are in memory at once, whatever the size of the tree.
         let (state_tx, state_rx) = crossbeam_channel::bounded(chan_size);
         let (job_tx, job_rx) = crossbeam_channel::bounded(chan_size);
+        let mut diff = Vec::new();
+        let mut actor_count = 0;
+        let mut job_error = None;
+        let mut export_error = None;
+        let migrations = &self.migrations;
 
+        let export_heads = export.is_some();
         pool.scope(|s| {
-            let store_clone = store.clone();
-
+            let source_store = store.clone();
+            let export_error = &mut export_error;
             s.spawn(move |_| {
-                // TODO: actors_in (StateTree) could use an iterator here.
                 actors_in
                     .for_each(|addr, state| {
+                        if export_heads {
+                            // reading the head through the store exports it, the
+                            // migration itself goes on if it's missing
+                            match source_store.get(&state.state) {
+                                Ok(Some(_)) => {}
+                                Ok(None) => {
+                                    export_error.get_or_insert(MigrationError::Export(format!(
+                                        "missing actor head {}",
+                                        state.state
+                                    )));
+                                }
+                                Err(e) => {
+                                    export_error.get_or_insert(MigrationError::BlockStoreRead(
+                                        e.to_string(),
+                                    ));
+                                }
+                            }
+                        }
                         state_tx
                             .send((addr, state.clone()))
                             .expect("failed sending actor state through channel");
@@ -114,37 +270,56 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
                     .expect("Failed iterating over actor state");
             });
 
-            s.spawn(move |scope| {
-                while let Ok((address, state)) = state_rx.recv() {
-                    let job_tx = job_tx.clone();
-                    let store_clone = store_clone.clone();
-                    let migrator = self.migrations.get(&state.code).cloned().unwrap();
-                    scope.spawn(move |_| {
-                        let job = MigrationJob {
-                            address: forest_shim::address::Address::from(address).into(),
-                            actor_state: state.into(),
-                            actor_migration: migrator,
+            for _ in 0..threads - 2 {
+                let state_rx = state_rx.clone();
+                let job_tx = job_tx.clone();
+                let store = store.clone();
+                s.spawn(move |_| {
+                    while let Ok((address, state)) = state_rx.recv() {
+                        let job_output = match migrations.get(&state.code) {
+                            Some(migrator) => MigrationJob {
+                                address: forest_shim::address::Address::from(address).into(),
+                                actor_state: state.into(),
+                                actor_migration: migrator.clone(),
+                            }
+                            .run(store.clone(), prior_epoch),
+                            None => Err(MigrationError::MigratorNotFound(state.code)),
                         };
 
-                        let job_output = job.run(store_clone, prior_epoch).unwrap_or_else(|e| {
-                            panic!(
-                                "failed executing job for address: {address}, Reason: {e}"
-                            )
-                        });
-
                         job_tx.send(job_output).unwrap_or_else(|_| {
                             panic!("failed sending job output for address: {address}")
                         });
-                    });
-                }
-                drop(job_tx);
-            });
+                    }
+                });
+            }
+            // the walk fails instead of blocking forever if every worker is gone
+            drop(state_rx);
+            drop(job_tx);
 
             while let Ok(job_output) = job_rx.recv() {
+                if let Some(export) = export.as_mut() {
+                    export.write_pending();
+                }
+                // keep draining the channel on failure so that no job blocks
                 let MigrationJobOutput {
                     address,
                     actor_state,
-                } = job_output;
+                    prior_code,
+                } = match job_output {
+                    Ok(output) => output,
+                    Err(e) => {
+                        job_error.get_or_insert(e);
+                        continue;
+                    }
+                };
+                actor_count += 1;
+                if record_diff && prior_code != actor_state.code {
+                    diff.push(CodeChange {
+                        address,
+                        old_code: prior_code,
+                        new_code: actor_state.code,
+                    });
+                }
                 actors_out
                     .set_actor(&address, actor_state.into())
                     .unwrap_or_else(|e| {
@@ -155,12 +330,196 @@ impl<BS: Blockstore + Clone + Send + Sync> StateMigration<BS> {
             }
         });
 
-        actors_out
+        let export_result = export.map_or(Ok(()), Exporter::finish);
+        if let Some(e) = job_error.or(export_error) {
+            return Err(e);
+        }
+        export_result?;
+
+        let root = actors_out
             .flush()
-            .map_err(|e| MigrationError::FlushFailed(e.to_string()))
+            .map_err(|e| MigrationError::FlushFailed(e.to_string()))?;
+        Ok(MigrationOutcome {
+            root,
+            diff,
+            actor_count,
+        })
+    }
+
+    /// Capacity of the channels between the state tree walk, the workers and
+    /// the collector.
+    fn chan_size(&self) -> usize {
+        self.channel_capacity.unwrap_or(num_cpus::get() / 2)
     }
 }
 
+/// Number of threads running a migration. The state producer and the output
+/// collector each block a thread for the whole migration, the remaining
+/// threads run migration jobs.
+fn migration_threads() -> usize {
+    num_cpus::get().max(4)
+}
+
+struct MigrationOutcome {
+    root: Cid,
+    diff: Vec<CodeChange>,
+    actor_count: usize,
+}
+
+/// Size of the state tree a migration would produce, see
+/// [`StateMigration::dry_run`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DryRunEstimate {
+    pub actor_count: usize,
+    pub estimated_bytes: usize,
+}
+
+/// Blockstore the actor migrators and the new state tree of a migration work
+/// on. Reads go to the inner store, and so do writes unless migrating in a
+/// [`StateMigration::dry_run`], which only records the CID and size of each
+/// written block. In [`StateMigration::migrate_state_tree_with_export`], every
+/// block read is also sent to the export.
+pub struct MigrationStore<BS> {
+    inner: Arc<BS>,
+    dry_run: Option<Arc<Mutex<HashMap<Cid, usize>>>>,
+    export: Option<Sender<(Cid, Vec<u8>)>>,
+}
+
+impl<BS> Clone for MigrationStore<BS> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            dry_run: self.dry_run.clone(),
+            export: self.export.clone(),
+        }
+    }
+}
+
+impl<BS> MigrationStore<BS> {
+    fn new(inner: Arc<BS>) -> Self {
+        Self {
+            inner,
+            dry_run: None,
+            export: None,
+        }
+    }
+
+    fn dry_run(inner: Arc<BS>) -> Self {
+        Self {
+            inner,
+            dry_run: Some(Default::default()),
+            export: None,
+        }
+    }
+
+    /// Creates a store sending every block read from it to the returned
+    /// channel.
+    fn exporting(inner: Arc<BS>) -> (Self, Receiver<(Cid, Vec<u8>)>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let store = Self {
+            inner,
+            dry_run: None,
+            export: Some(tx),
+        };
+        (store, rx)
+    }
+
+    /// Total size of the distinct blocks written so far in a dry run.
+    fn written_bytes(&self) -> usize {
+        self.dry_run
+            .as_ref()
+            .map_or(0, |written| written.lock().unwrap().values().sum())
+    }
+}
+
+impl<BS: Blockstore> Blockstore for MigrationStore<BS> {
+    fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let block = self.inner.get(k)?;
+        if let (Some(export), Some(block)) = (&self.export, &block) {
+            // nothing reads through the store once the export is finished
+            let _ = export.send((*k, block.clone()));
+        }
+        Ok(block)
+    }
+
+    fn has(&self, k: &Cid) -> anyhow::Result<bool> {
+        self.inner.has(k)
+    }
+
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+        match &self.dry_run {
+            Some(written) => {
+                written.lock().unwrap().insert(*k, block.len());
+                Ok(())
+            }
+            None => self.inner.put_keyed(k, block),
+        }
+    }
+}
+
+/// Writes the blocks read through an exporting [`MigrationStore`] to a CARv1
+/// file, once each.
+struct Exporter<'a> {
+    blocks: Receiver<(Cid, Vec<u8>)>,
+    written: HashSet<Cid>,
+    out: &'a mut (dyn Write + Send),
+    error: Option<MigrationError>,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(blocks: Receiver<(Cid, Vec<u8>)>, out: &'a mut (dyn Write + Send)) -> Self {
+        Self {
+            blocks,
+            written: HashSet::new(),
+            out,
+            error: None,
+        }
+    }
+
+    /// Writes the blocks read since the last call. Once writing failed, blocks
+    /// are dropped and the error is returned by [`Exporter::finish`].
+    fn write_pending(&mut self) {
+        for (cid, block) in self.blocks.try_iter() {
+            if self.error.is_some() || !self.written.insert(cid) {
+                continue;
+            }
+            if let Err(e) = write_car_frame(&[&cid.to_bytes(), &block], self.out) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(mut self) -> MigrationResult<()> {
+        self.write_pending();
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+/// Writes a CARv1 header with `root` as its only root to `out`.
+fn write_car_header(root: &Cid, out: &mut dyn Write) -> MigrationResult<()> {
+    let header = fvm_ipld_encoding::to_vec(&CarHeader::from(vec![*root]))
+        .map_err(|e| MigrationError::Export(e.to_string()))?;
+    write_car_frame(&[&header], out)
+}
+
+/// Writes `parts` to `out`, prefixed with the varint length of them all as a
+/// CARv1 header or section.
+fn write_car_frame(parts: &[&[u8]], out: &mut dyn Write) -> MigrationResult<()> {
+    let len = parts.iter().map(|part| part.len()).sum();
+    let mut buf = unsigned_varint::encode::usize_buffer();
+    out.write_all(unsigned_varint::encode::usize(len, &mut buf))
+        .and_then(|_| parts.iter().try_for_each(|part| out.write_all(part)))
+        .map_err(|e| MigrationError::Export(e.to_string()))
+}
+
+/// Code CID transition of a single actor during a state migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeChange {
+    pub address: Address,
+    pub old_code: Cid,
+    pub new_code: Cid,
+}
+
 #[allow(dead_code)] // future migrations might need the fields.
 pub struct ActorMigrationInput {
     /// Actor's address
@@ -205,15 +564,15 @@ impl<BS: Blockstore + Send + Sync> MigrationJob<BS> {
                     prior_epoch,
                 },
             )
-            .map_err(|e| {
-                MigrationError::MigrationJobRun(format!(
-                    "state migration failed for {} actor, addr {}:{}",
-                    self.actor_state.code, self.address, e
-                ))
+            .map_err(|e| MigrationError::InActor {
+                address: self.address,
+                code: self.actor_state.code,
+                source: Box::new(e),
             })?;
 
         let migration_job_result = MigrationJobOutput {
             address: self.address,
+            prior_code: self.actor_state.code,
             actor_state: <ActorState as Inner>::FVM::new(
                 result.new_code_cid,
                 result.new_head,
@@ -232,6 +591,35 @@ impl<BS: Blockstore + Send + Sync> MigrationJob<BS> {
 struct MigrationJobOutput {
     address: Address,
     actor_state: ActorState,
+    prior_code: Cid,
+}
+
+/// Copies the HAMT at `root` into a new HAMT with the same bit width, applying
+/// `f` to every value. Returns the root of the new HAMT.
+pub fn migrate_hamt_transform<BS, V, U, F>(
+    store: &BS,
+    root: &Cid,
+    bitwidth: u32,
+    f: F,
+) -> MigrationResult<Cid>
+where
+    BS: Blockstore,
+    V: Serialize + DeserializeOwned,
+    U: Serialize + DeserializeOwned,
+    F: Fn(&V) -> MigrationResult<U>,
+{
+    let in_hamt = Hamt::<_, V>::load_with_bit_width(root, store, bitwidth)
+        .map_err(|e| MigrationError::BlockStoreRead(e.to_string()))?;
+    let mut out_hamt = Hamt::<_, U>::new_with_bit_width(store, bitwidth);
+    in_hamt
+        .for_each(|key, value| {
+            out_hamt.set(key.clone(), f(value)?)?;
+            Ok(())
+        })
+        .map_err(|e| MigrationError::MigrationJobRun(e.to_string()))?;
+    out_hamt
+        .flush()
+        .map_err(|e| MigrationError::BlockStoreWrite(e.to_string()))
 }
 
 #[allow(dead_code)]
@@ -256,3 +644,457 @@ impl<BS: Blockstore + Send + Sync> ActorMigration<BS> for NilMigrator {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    };
+
+    use cid::multihash::{Code::Blake2b256, MultihashDigest};
+    use forest_db::MemoryDB;
+    use fvm_ipld_hamt::BytesKey;
+    use fvm_shared::state::StateTreeVersion;
+
+    use super::*;
+
+    fn code_cid(name: &str) -> Cid {
+        Cid::new_v1(0x55, Blake2b256.digest(name.as_bytes()))
+    }
+
+    struct FailingMigrator;
+
+    impl<BS: Blockstore + Send + Sync> ActorMigration<BS> for FailingMigrator {
+        fn migrate_state(
+            &self,
+            _store: Arc<BS>,
+            _input: ActorMigrationInput,
+        ) -> MigrationResult<MigrationOutput> {
+            Err(MigrationError::BlockStoreRead("no market state".into()))
+        }
+    }
+
+    /// Migrates actors to the `market/3` code, writing a new head holding the
+    /// actor address.
+    struct WritingMigrator;
+
+    impl<BS: Blockstore + Send + Sync> ActorMigration<BS> for WritingMigrator {
+        fn migrate_state(
+            &self,
+            store: Arc<BS>,
+            input: ActorMigrationInput,
+        ) -> MigrationResult<MigrationOutput> {
+            let data = input.address.to_bytes();
+            let new_head = Cid::new_v1(0x55, Blake2b256.digest(&data));
+            store
+                .put_keyed(&new_head, &data)
+                .map_err(|e| MigrationError::BlockStoreWrite(e.to_string()))?;
+            Ok(MigrationOutput {
+                new_code_cid: code_cid("market/3"),
+                new_head,
+            })
+        }
+    }
+
+    /// Migrates actors to the `market/3` code, reading the given block on every
+    /// call.
+    struct ReadingMigrator(Cid);
+
+    impl<BS: Blockstore + Send + Sync> ActorMigration<BS> for ReadingMigrator {
+        fn migrate_state(
+            &self,
+            store: Arc<BS>,
+            input: ActorMigrationInput,
+        ) -> MigrationResult<MigrationOutput> {
+            store
+                .get(&self.0)
+                .map_err(|e| MigrationError::BlockStoreRead(e.to_string()))?
+                .ok_or_else(|| MigrationError::BlockStoreRead(format!("missing {}", self.0)))?;
+            Ok(MigrationOutput {
+                new_code_cid: code_cid("market/3"),
+                new_head: input.head,
+            })
+        }
+    }
+
+    /// Actors taken from the state tree walk and actors migrated so far.
+    #[derive(Default)]
+    struct Progress {
+        taken: usize,
+        migrated: usize,
+        /// Most actors taken and not migrated yet at once.
+        peak_in_flight: usize,
+    }
+
+    /// Migrates actors to the `market/2` code, holding every call back until
+    /// `release_at` of them are waiting.
+    struct BarrierMigrator {
+        release_at: usize,
+        waiting: (Mutex<usize>, Condvar),
+        progress: Arc<Mutex<Progress>>,
+    }
+
+    impl BarrierMigrator {
+        fn new(release_at: usize, progress: Arc<Mutex<Progress>>) -> Self {
+            Self {
+                release_at,
+                waiting: Default::default(),
+                progress,
+            }
+        }
+    }
+
+    impl<BS: Blockstore + Send + Sync> ActorMigration<BS> for BarrierMigrator {
+        fn migrate_state(
+            &self,
+            _store: Arc<BS>,
+            input: ActorMigrationInput,
+        ) -> MigrationResult<MigrationOutput> {
+            let (count, released) = &self.waiting;
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            if *count >= self.release_at {
+                released.notify_all();
+            }
+            while *count < self.release_at {
+                count = released.wait(count).unwrap();
+            }
+            self.progress.lock().unwrap().migrated += 1;
+            Ok(MigrationOutput {
+                new_code_cid: code_cid("market/2"),
+                new_head: input.head,
+            })
+        }
+    }
+
+    /// Counts the blocks written to the underlying store.
+    #[derive(Clone, Default)]
+    struct CountingStore {
+        db: MemoryDB,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Blockstore for CountingStore {
+        fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+            self.db.get(k)
+        }
+
+        fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.db.put_keyed(k, block)
+        }
+    }
+
+    /// Counts the reads of the `head` block shared by the actors of a
+    /// [`state_tree`] as actors taken from its walk, which an export reads as
+    /// they're taken.
+    #[derive(Clone)]
+    struct TrackingStore {
+        db: MemoryDB,
+        head: Cid,
+        progress: Arc<Mutex<Progress>>,
+    }
+
+    impl Blockstore for TrackingStore {
+        fn get(&self, k: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+            if k == &self.head {
+                let mut progress = self.progress.lock().unwrap();
+                progress.taken += 1;
+                progress.peak_in_flight = progress
+                    .peak_in_flight
+                    .max(progress.taken - progress.migrated);
+            }
+            self.db.get(k)
+        }
+
+        fn put_keyed(&self, k: &Cid, block: &[u8]) -> anyhow::Result<()> {
+            self.db.put_keyed(k, block)
+        }
+    }
+
+    /// Creates a migration using `market_migrator` for the `market` code and
+    /// nil migrators keeping the code for the other `ACTORS_COUNT - 1` codes.
+    fn test_migration<BS: Blockstore + Clone + Send + Sync>(
+        market: Cid,
+        market_migrator: Migrator<BS>,
+    ) -> StateMigration<BS> {
+        let mut migration = StateMigration::new();
+        migration.add_migrator(market, market_migrator);
+        for i in 1..ACTORS_COUNT {
+            let code = code_cid(&format!("actor/{i}"));
+            migration.add_migrator(code, nil_migrator(code));
+        }
+        migration
+    }
+
+    /// Creates a flushed state tree holding the given actors, which share the
+    /// `head` block.
+    fn state_tree<BS: Blockstore + Clone>(store: &BS, actors: &[(Address, Cid)]) -> StateTree<BS> {
+        store.put_keyed(&code_cid("head"), b"head").unwrap();
+        let mut tree = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        for (addr, code) in actors {
+            let state: ActorState = <ActorState as Inner>::FVM::new(
+                *code,
+                code_cid("head"),
+                Default::default(),
+                0,
+                None,
+            )
+            .into();
+            tree.set_actor(addr, state.into()).unwrap();
+        }
+        let root = tree.flush().unwrap();
+        StateTree::new_from_root(store.clone(), &root).unwrap()
+    }
+
+    #[test]
+    fn diff_lists_changed_code_only() {
+        let store = MemoryDB::default();
+        let (market, new_market) = (code_cid("market/2"), code_cid("market/3"));
+        let account = code_cid("actor/1");
+        let (market_addr, account_addr) = (Address::new_id(5), Address::new_id(100));
+
+        let actors_in = state_tree(&store, &[(market_addr, market), (account_addr, account)]);
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        let (_, diff) = test_migration(market, nil_migrator(new_market))
+            .migrate_state_tree_with_diff(Arc::new(store), 0, actors_in, actors_out)
+            .unwrap();
+
+        assert_eq!(
+            diff,
+            vec![CodeChange {
+                address: market_addr,
+                old_code: market,
+                new_code: new_market,
+            }]
+        );
+    }
+
+    #[test]
+    fn failed_job_names_actor() {
+        let store = MemoryDB::default();
+        let market = code_cid("market/2");
+        let (market_addr, account_addr) = (Address::new_id(5), Address::new_id(100));
+
+        let actors_in = state_tree(
+            &store,
+            &[(market_addr, market), (account_addr, code_cid("actor/1"))],
+        );
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        let err = test_migration(market, Arc::new(FailingMigrator))
+            .migrate_state_tree(Arc::new(store), 0, actors_in, actors_out)
+            .unwrap_err();
+
+        match err {
+            MigrationError::InActor {
+                address,
+                code,
+                source,
+            } => {
+                assert_eq!(address, market_addr);
+                assert_eq!(code, market);
+                assert!(matches!(*source, MigrationError::BlockStoreRead(_)));
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn unknown_code_fails_migration() {
+        let store = MemoryDB::default();
+        let market = code_cid("market/2");
+        let unknown = code_cid("actor/unknown");
+        let actors_in = state_tree(
+            &store,
+            &[
+                (Address::new_id(5), market),
+                (Address::new_id(100), unknown),
+            ],
+        );
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        let err = test_migration(market, nil_migrator(code_cid("market/3")))
+            .migrate_state_tree(Arc::new(store), 0, actors_in, actors_out)
+            .unwrap_err();
+
+        assert!(matches!(err, MigrationError::MigratorNotFound(code) if code == unknown));
+    }
+
+    /// Migrates 4096 actors with the given channel capacity. Returns the most
+    /// actors taken from the walk and not migrated yet at once, along with the
+    /// bound the channels should keep them to.
+    fn peak_in_flight(capacity: Option<usize>) -> (usize, usize) {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let store = TrackingStore {
+            db: MemoryDB::default(),
+            head: code_cid("head"),
+            progress: progress.clone(),
+        };
+        let market = code_cid("market/2");
+        let actors: Vec<_> = (0..4096).map(|id| (Address::new_id(id), market)).collect();
+        let root = state_tree(&store, &actors).flush().unwrap();
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+
+        // no job finishes before every worker holds an actor
+        let threads = migration_threads();
+        let migrator = BarrierMigrator::new(threads - 2, progress.clone());
+        let mut migration = test_migration(market, Arc::new(migrator));
+        if let Some(capacity) = capacity {
+            migration.set_channel_capacity(capacity);
+        }
+        migration
+            .migrate_state_tree_with_export(
+                Arc::new(store),
+                0,
+                &root,
+                actors_out,
+                &mut std::io::sink(),
+            )
+            .unwrap();
+
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.taken, actors.len());
+        assert_eq!(progress.migrated, actors.len());
+        (progress.peak_in_flight, 2 * migration.chan_size() + threads)
+    }
+
+    #[test]
+    fn migration_streams_actors() {
+        let (peak, bound) = peak_in_flight(None);
+        assert!(peak >= migration_threads() - 2);
+        assert!(
+            peak <= bound,
+            "held {peak} actors at once, expected at most {bound}"
+        );
+    }
+
+    #[test]
+    fn channel_capacity_bounds_tree_walk() {
+        for capacity in [1, 16] {
+            let (peak, bound) = peak_in_flight(Some(capacity));
+            assert!(
+                peak <= bound,
+                "held {peak} actors at once with a capacity of {capacity}, expected at most {bound}"
+            );
+        }
+    }
+
+    #[test]
+    fn dry_run_counts_actors_without_writing() {
+        let store = CountingStore::default();
+        let market = code_cid("market/2");
+        let actors: Vec<_> = (0..100)
+            .map(|id| {
+                let code = match id % 10 {
+                    0 => market,
+                    i => code_cid(&format!("actor/{i}")),
+                };
+                (Address::new_id(id), code)
+            })
+            .collect();
+        let root = state_tree(&store, &actors).flush().unwrap();
+
+        store.writes.store(0, Ordering::SeqCst);
+        let estimate = test_migration::<CountingStore>(market, Arc::new(WritingMigrator))
+            .dry_run(Arc::new(store.clone()), 0, &root, StateTreeVersion::V4)
+            .unwrap();
+        assert_eq!(store.writes.load(Ordering::SeqCst), 0);
+        assert_eq!(estimate.actor_count, actors.len());
+
+        // the new market heads are counted along with the new state tree
+        let head_bytes: usize = (0..100)
+            .step_by(10)
+            .map(|id| Address::new_id(id).to_bytes().len())
+            .sum();
+        assert!(estimate.estimated_bytes > head_bytes);
+
+        // the same migration writes the new heads when it isn't a dry run
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        test_migration(market, Arc::new(WritingMigrator))
+            .migrate_state_tree(
+                Arc::new(store.clone()),
+                0,
+                StateTree::new_from_root(store.clone(), &root).unwrap(),
+                actors_out,
+            )
+            .unwrap();
+        assert!(store.writes.load(Ordering::SeqCst) > 10);
+    }
+
+    #[test]
+    fn export_writes_car_of_blocks_read() {
+        let store = MemoryDB::default();
+        let market = code_cid("market/2");
+        let params = code_cid("params");
+        store.put_keyed(&params, b"params").unwrap();
+        let mut tree = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        for id in 0..10u64 {
+            let data = id.to_be_bytes();
+            let head = Cid::new_v1(0x55, Blake2b256.digest(&data));
+            store.put_keyed(&head, &data).unwrap();
+            let state: ActorState =
+                <ActorState as Inner>::FVM::new(market, head, Default::default(), 0, None).into();
+            tree.set_actor(&Address::new_id(id), state.into()).unwrap();
+        }
+        let root = tree.flush().unwrap();
+
+        let mut export = Vec::new();
+        let actors_out = StateTree::new(store.clone(), StateTreeVersion::V4).unwrap();
+        test_migration(market, Arc::new(ReadingMigrator(params)))
+            .migrate_state_tree_with_export(
+                Arc::new(store.clone()),
+                0,
+                &root,
+                actors_out,
+                &mut export,
+            )
+            .unwrap();
+
+        let (len, rest) = unsigned_varint::decode::usize(&export).unwrap();
+        let (header, mut rest) = rest.split_at(len);
+        let header: CarHeader = fvm_ipld_encoding::from_slice(header).unwrap();
+        assert_eq!(header.roots, vec![root]);
+
+        let exported = MemoryDB::default();
+        let mut cids = HashSet::new();
+        while !rest.is_empty() {
+            let (len, tail) = unsigned_varint::decode::usize(rest).unwrap();
+            let (mut section, tail) = tail.split_at(len);
+            let cid = Cid::read_bytes(&mut section).unwrap();
+            assert_eq!(store.get(&cid).unwrap().as_deref(), Some(section));
+            assert!(cids.insert(cid), "{cid} exported twice");
+            exported.put_keyed(&cid, section).unwrap();
+            rest = tail;
+        }
+
+        // the source state tree loads from the export along with the actor heads
+        let mut heads = HashSet::new();
+        let tree = StateTree::new_from_root(&exported, &root).unwrap();
+        tree.for_each(|_, state| {
+            assert!(exported.has(&state.state)?);
+            heads.insert(state.state);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(heads.len(), 10);
+        assert!(cids.contains(&params));
+    }
+
+    #[test]
+    fn hamt_transform_applies_to_each_value() {
+        let store = MemoryDB::default();
+        let key = |i: u64| BytesKey(i.to_be_bytes().to_vec());
+        let mut hamt = Hamt::<_, u64>::new_with_bit_width(&store, 5);
+        for i in 0..10 {
+            hamt.set(key(i), i).unwrap();
+        }
+        let root = hamt.flush().unwrap();
+
+        let new_root = migrate_hamt_transform(&store, &root, 5, |v: &u64| Ok(v * 2)).unwrap();
+
+        let migrated = Hamt::<_, u64>::load_with_bit_width(&new_root, &store, 5).unwrap();
+        for i in 0..10 {
+            assert_eq!(migrated.get(&key(i)).unwrap(), Some(&(i * 2)));
+        }
+    }
+}